@@ -1,8 +1,61 @@
 use fpnum::FPNum;
-use std::{collections::BinaryHeap, num::NonZeroU16, ops::Add};
+use std::{
+    collections::BinaryHeap,
+    convert::TryFrom,
+    fmt::{Debug, Display},
+    hash::Hash,
+    num::{NonZeroU16, NonZeroU32},
+    ops::Add,
+};
 
 pub type GearId = NonZeroU16;
 
+/// Id types a `GearDataManager` can be keyed by. `GearId` is the default, `NonZeroU32`
+/// allows more than 65535 gears. Blocks store the ids inline, so they must not need
+/// more than 4-byte alignment.
+pub trait GearIdType: Copy + Eq + Ord + Hash + Debug + Display + 'static {
+    type Raw: Copy;
+    /// The largest id, which is also the largest lookup table size.
+    const MAX: usize;
+
+    fn new(raw: Self::Raw) -> Option<Self>;
+    fn get(self) -> Self::Raw;
+    fn from_usize(value: usize) -> Option<Self>;
+    fn to_usize(self) -> usize;
+}
+
+macro_rules! gear_id_type_impl {
+    ($id: ty, $raw: ty) => {
+        impl GearIdType for $id {
+            type Raw = $raw;
+            const MAX: usize = <$raw>::MAX as usize;
+
+            #[inline]
+            fn new(raw: $raw) -> Option<Self> {
+                <$id>::new(raw)
+            }
+
+            #[inline]
+            fn get(self) -> $raw {
+                <$id>::get(self)
+            }
+
+            #[inline]
+            fn from_usize(value: usize) -> Option<Self> {
+                <$raw>::try_from(value).ok().and_then(<$id>::new)
+            }
+
+            #[inline]
+            fn to_usize(self) -> usize {
+                <$id>::get(self) as usize
+            }
+        }
+    };
+}
+
+gear_id_type_impl!(NonZeroU16, u16);
+gear_id_type_impl!(NonZeroU32, u32);
+
 #[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
 #[repr(transparent)]
 pub struct Millis(u32);
@@ -1,61 +1,169 @@
-use super::common::GearId;
+mod serialize;
+
+pub use self::serialize::{DeserializeError, Snapshot};
+
+use super::common::{GearId, GearIdType};
 use std::{
-    any::TypeId,
-    fmt::{Debug, Error, Formatter},
+    alloc::{alloc, dealloc, handle_alloc_error, Layout},
+    any::{type_name, Any, TypeId},
+    cell::Cell,
+    cmp::{max, min},
+    collections::{HashMap, HashSet},
+    fmt::{Debug, Error, Formatter, Write},
+    hash::{Hash, Hasher},
     marker::PhantomData,
-    mem::{align_of, size_of, MaybeUninit},
+    mem::{align_of, forget, needs_drop, size_of},
     num::NonZeroU16,
-    ptr::{copy_nonoverlapping, null_mut, NonNull},
+    ops::{Deref, DerefMut, Range, RangeInclusive},
+    ptr::{copy, copy_nonoverlapping, drop_in_place, null_mut, swap_nonoverlapping, NonNull},
     slice,
+    sync::atomic::{AtomicU64, Ordering},
 };
 
+pub type ComponentId = u16;
+
 pub trait TypeTuple: Sized {
     fn get_types(types: &mut Vec<TypeId>);
+
+    /// Pushes whether each element is borrowed mutably, in the order of `get_types`.
+    fn get_mutability(mutability: &mut Vec<bool>);
 }
 
 impl TypeTuple for () {
     fn get_types(_types: &mut Vec<TypeId>) {}
+
+    fn get_mutability(_mutability: &mut Vec<bool>) {}
 }
 
 impl<T: 'static> TypeTuple for &T {
     fn get_types(types: &mut Vec<TypeId>) {
         types.push(TypeId::of::<T>());
     }
+
+    fn get_mutability(mutability: &mut Vec<bool>) {
+        mutability.push(false);
+    }
+}
+
+pub trait TypeElement: Sized {
+    type Component: 'static;
+    type Slice<'a>;
+    const MUTABLE: bool;
+
+    /// # Safety
+    /// `ptr` must point to a component array valid for at least `index + 1` elements.
+    unsafe fn get(ptr: *mut u8, index: usize) -> Self;
+
+    /// # Safety
+    /// `ptr` must point to a component array valid for `count` elements.
+    unsafe fn slice<'a>(ptr: *mut u8, count: usize) -> Self::Slice<'a>;
+}
+
+impl<T: 'static> TypeElement for &T {
+    type Component = T;
+    type Slice<'a> = &'a [T];
+    const MUTABLE: bool = false;
+
+    #[inline]
+    unsafe fn get(ptr: *mut u8, index: usize) -> Self {
+        &*(ptr as *const T).add(index)
+    }
+
+    #[inline]
+    unsafe fn slice<'a>(ptr: *mut u8, count: usize) -> Self::Slice<'a> {
+        slice::from_raw_parts(ptr as *const T, count)
+    }
+}
+
+impl<T: 'static> TypeElement for &mut T {
+    type Component = T;
+    type Slice<'a> = &'a mut [T];
+    const MUTABLE: bool = true;
+
+    #[inline]
+    unsafe fn get(ptr: *mut u8, index: usize) -> Self {
+        &mut *(ptr as *mut T).add(index)
+    }
+
+    #[inline]
+    unsafe fn slice<'a>(ptr: *mut u8, count: usize) -> Self::Slice<'a> {
+        slice::from_raw_parts_mut(ptr as *mut T, count)
+    }
 }
 
+pub trait RefElement: TypeElement {}
+
+impl<T: 'static> RefElement for &T {}
+
 pub trait TypeIter: TypeTuple {
-    unsafe fn iter<F: FnMut(GearId, Self)>(slices: &[*mut u8], count: usize, f: F);
+    /// # Safety
+    /// `slices` must hold the gear id array followed by one component array per tuple element,
+    /// each valid for `count` elements of the corresponding type.
+    unsafe fn iter<Id: Copy, F: FnMut(Id, Self)>(slices: &[*mut u8], count: usize, f: F);
+
+    /// # Safety
+    /// Same as `iter`, with `index` less than the element count of the slices.
+    unsafe fn get<Id: Copy>(slices: &[*mut u8], index: usize) -> (Id, Self);
+
+    type Slices<'a>;
+
+    /// # Safety
+    /// Same as `iter`; the returned slices must not outlive the block storage.
+    unsafe fn slices<'a>(slices: &[*mut u8], count: usize) -> Self::Slices<'a>;
+}
+
+pub trait TupleOfRefs: TypeIter {}
+
+pub trait CopyTuple: Sized {
+    fn get_types(types: &mut Vec<TypeId>);
+
+    /// # Safety
+    /// Same as `TypeIter::get`.
+    unsafe fn read<Id: Copy>(slices: &[*mut u8], index: usize) -> (Id, Self);
 }
 
 macro_rules! type_tuple_impl {
     ($($n: literal: $t: ident),+) => {
-        impl<$($t: 'static),+> TypeTuple for ($(&$t),+,) {
+        impl<$($t: TypeElement),+> TypeTuple for ($($t),+,) {
             fn get_types(types: &mut Vec<TypeId>) {
-                $(types.push(TypeId::of::<$t>()));+
+                $(types.push(TypeId::of::<$t::Component>()));+
+            }
+
+            fn get_mutability(mutability: &mut Vec<bool>) {
+                $(mutability.push($t::MUTABLE));+
             }
         }
 
-        impl<$($t: 'static),+> TypeIter for ($(&$t),+,) {
-            unsafe fn iter<F: FnMut(GearId, Self)>(slices: &[*mut u8], count: usize, mut f: F) {
+        impl<$($t: TypeElement),+> TypeIter for ($($t),+,) {
+            unsafe fn iter<Id: Copy, F: FnMut(Id, Self)>(slices: &[*mut u8], count: usize, mut f: F) {
                 for i in 0..count {
-                    f(*(*slices.get_unchecked(0) as *const GearId).add(i),
-                      ($(&*(*slices.get_unchecked($n + 1) as *mut $t).add(i)),+,));
+                    f(*(*slices.get_unchecked(0) as *const Id).add(i),
+                      ($($t::get(*slices.get_unchecked($n + 1), i)),+,));
                 }
             }
+
+            unsafe fn get<Id: Copy>(slices: &[*mut u8], index: usize) -> (Id, Self) {
+                (*(*slices.get_unchecked(0) as *const Id).add(index),
+                 ($($t::get(*slices.get_unchecked($n + 1), index)),+,))
+            }
+
+            type Slices<'a> = ($($t::Slice<'a>),+,);
+
+            unsafe fn slices<'a>(slices: &[*mut u8], count: usize) -> Self::Slices<'a> {
+                ($($t::slice(*slices.get_unchecked($n + 1), count)),+,)
+            }
         }
 
-        impl<$($t: 'static),+> TypeTuple for ($(&mut $t),+,) {
+        impl<$($t: RefElement),+> TupleOfRefs for ($($t),+,) {}
+
+        impl<$($t: Copy + 'static),+> CopyTuple for ($($t),+,) {
             fn get_types(types: &mut Vec<TypeId>) {
                 $(types.push(TypeId::of::<$t>()));+
             }
-        }
 
-        impl<$($t: 'static),+> TypeIter for ($(&mut $t),+,) {
-            unsafe fn iter<F: FnMut(GearId, Self)>(slices: &[*mut u8], count: usize, mut f: F) {
-                for i in 0..count {
-                    f(*(*slices.get_unchecked(0) as *const GearId).add(i),
-                      ($(&mut *(*slices.get_unchecked($n + 1) as *mut $t).add(i)),+,));
-                }
+            unsafe fn read<Id: Copy>(slices: &[*mut u8], index: usize) -> (Id, Self) {
+                (*(*slices.get_unchecked(0) as *const Id).add(index),
+                 ($(*(*slices.get_unchecked($n + 1) as *const $t).add(index)),+,))
             }
         }
     }
@@ -68,18 +176,25 @@ type_tuple_impl!(0: A, 1: B, 2: C, 3: D);
 type_tuple_impl!(0: A, 1: B, 2: C, 3: D, 4: E);
 
 const BLOCK_SIZE: usize = 32768;
+const COMPACTION_THRESHOLD: f32 = 0.5;
 
-struct DataBlock {
+struct DataBlock<Id> {
     max_elements: u16,
     elements_count: u16,
-    data: Box<[u8; BLOCK_SIZE]>,
-    component_blocks: [Option<NonNull<u8>>; 64],
+    data: NonNull<u8>,
+    component_blocks: Box<[Option<NonNull<u8>>]>,
     element_sizes: Box<[u16]>,
+    disabled_components: Vec<u64>,
+    generations: Vec<u64>,
+    versions: Vec<u32>,
+    //the storage belongs to an image passed to load_mmap and is never released
+    attached: bool,
+    id_type: PhantomData<Id>,
 }
 
-impl Unpin for DataBlock {}
+impl<Id> Unpin for DataBlock<Id> {}
 
-impl Debug for DataBlock {
+impl<Id: GearIdType> Debug for DataBlock<Id> {
     fn fmt(&self, f: &mut Formatter) -> Result<(), Error> {
         write!(
             f,
@@ -89,7 +204,7 @@ impl Debug for DataBlock {
         write!(f, "\tIDs: [")?;
         let id_slice = unsafe {
             slice::from_raw_parts(
-                self.data.as_ptr() as *const GearId,
+                self.data.as_ptr() as *const Id,
                 self.elements_count as usize,
             )
         };
@@ -103,7 +218,7 @@ impl Debug for DataBlock {
                 let slice = unsafe {
                     slice::from_raw_parts(
                         ptr.as_ptr(),
-                        (self.elements_count * self.element_sizes[type_index]) as usize,
+                        self.elements_count as usize * self.element_sizes[type_index] as usize,
                     )
                 };
                 for byte in slice {
@@ -116,64 +231,91 @@ impl Debug for DataBlock {
     }
 }
 
-impl DataBlock {
-    fn new(mask: u64, element_sizes: &[u16], element_alignments: &[u8]) -> Self {
-        let total_padding: usize = element_alignments.iter().map(|x| *x as usize).sum();
-        let total_size: u16 = element_sizes
-            .iter()
-            .enumerate()
-            .filter(|(i, _)| mask & (1 << *i as u64) != 0)
-            .map(|(_, size)| *size)
-            .sum();
-        let max_elements =
-            ((BLOCK_SIZE - total_padding) / (total_size as usize + size_of::<GearId>())) as u16;
+/// Zero means that a single gear of the archetype does not fit in a block.
+fn block_capacity<Id>(mask: u64, element_sizes: &[u16], element_alignments: &[u8]) -> u16 {
+    let total_padding: usize = element_alignments
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| mask & (1 << *i as u64) != 0)
+        .map(|(_, alignment)| *alignment as usize)
+        .sum();
+    let total_size: usize = element_sizes
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| mask & (1 << *i as u64) != 0)
+        .map(|(_, size)| *size as usize)
+        .sum();
+    clamp_block_capacity(BLOCK_SIZE.saturating_sub(total_padding) / (total_size + size_of::<Id>()))
+}
+
+//u16::MAX is left out so that a one-based count of the elements never overflows
+fn clamp_block_capacity(capacity: usize) -> u16 {
+    min(capacity, u16::MAX as usize - 1) as u16
+}
+
+impl<Id: GearIdType> DataBlock<Id> {
+    fn new(data: NonNull<u8>, mask: u64, element_sizes: &[u16], element_alignments: &[u8]) -> Self {
+        let max_elements = block_capacity::<Id>(mask, element_sizes, element_alignments);
 
-        //ensure the block memory is aligned to GearId
-        let tmp_data: Box<[GearId; BLOCK_SIZE / size_of::<GearId>()]> =
-            Box::new(unsafe { MaybeUninit::uninit().assume_init() });
-        let mut data: Box<[u8; BLOCK_SIZE]> =
-            unsafe { Box::from_raw(Box::into_raw(tmp_data) as *mut [u8; BLOCK_SIZE]) };
+        let mut block = Self {
+            elements_count: 0,
+            max_elements,
+            data,
+            component_blocks: vec![None; element_sizes.len()].into_boxed_slice(),
+            element_sizes: Box::from(element_sizes),
+            disabled_components: Vec::with_capacity(max_elements as usize),
+            generations: Vec::with_capacity(max_elements as usize),
+            versions: Vec::with_capacity(max_elements as usize),
+            attached: false,
+            id_type: PhantomData,
+        };
+        block.layout_components(mask, element_alignments);
+        block
+    }
 
-        let mut blocks = [None; 64];
+    fn layout_components(&mut self, mask: u64, element_alignments: &[u8]) {
+        let mut blocks = vec![None; self.component_blocks.len()].into_boxed_slice();
         let mut address = unsafe {
-            data.as_mut_ptr()
-                .add(size_of::<GearId>() * max_elements as usize)
+            self.data
+                .as_ptr()
+                .add(size_of::<Id>() * self.max_elements as usize)
         };
 
-        for i in 0..element_sizes.len() {
+        for i in 0..self.element_sizes.len() {
             if mask & (1 << i as u64) != 0 {
                 unsafe {
                     address = address.add(address.align_offset(element_alignments[i] as usize));
                     blocks[i] = Some(NonNull::new_unchecked(address));
-                    address = address.add(element_sizes[i] as usize * max_elements as usize)
+                    address =
+                        address.add(self.element_sizes[i] as usize * self.max_elements as usize)
                 };
             }
         }
+        self.component_blocks = blocks;
+    }
 
-        Self {
-            elements_count: 0,
-            max_elements,
-            data,
-            component_blocks: blocks,
-            element_sizes: Box::from(element_sizes),
-        }
+    fn pointers_valid(&self) -> bool {
+        let start = self.data.as_ptr() as *const u8;
+        let data_range = start..start.wrapping_add(BLOCK_SIZE);
+        self.component_blocks
+            .iter()
+            .enumerate()
+            .filter_map(|(i, ptr)| ptr.map(|ptr| (i, ptr.as_ptr() as *const u8)))
+            .all(|(i, ptr)| {
+                let size = self.element_sizes[i] as usize * self.max_elements as usize;
+                data_range.contains(&ptr) && ptr.wrapping_add(size) <= data_range.end
+            })
     }
 
-    fn gear_ids(&self) -> &[GearId] {
+    fn gear_ids(&self) -> &[Id] {
         unsafe {
-            slice::from_raw_parts(
-                self.data.as_ptr() as *const GearId,
-                self.max_elements as usize,
-            )
+            slice::from_raw_parts(self.data.as_ptr() as *const Id, self.max_elements as usize)
         }
     }
 
-    fn gear_ids_mut(&mut self) -> &mut [GearId] {
+    fn gear_ids_mut(&mut self) -> &mut [Id] {
         unsafe {
-            slice::from_raw_parts_mut(
-                self.data.as_mut_ptr() as *mut GearId,
-                self.max_elements as usize,
-            )
+            slice::from_raw_parts_mut(self.data.as_ptr() as *mut Id, self.max_elements as usize)
         }
     }
 
@@ -182,6 +324,63 @@ impl DataBlock {
     }
 }
 
+#[derive(Clone, Copy, Debug)]
+struct SpatialBucket(u8);
+
+//stands in for an unregistered type so that the ids of the other types stay the same
+struct FreeSlot;
+
+fn is_free_slot(type_id: &TypeId) -> bool {
+    *type_id == TypeId::of::<FreeSlot>()
+}
+
+//reuses the slot of an unregistered type before growing the registry
+fn claim_slot(registry: &mut Vec<TypeId>, type_id: TypeId) -> Option<usize> {
+    match registry.iter().position(is_free_slot) {
+        Some(index) => {
+            registry[index] = type_id;
+            Some(index)
+        }
+        None if registry.len() >= 64 => None,
+        None => {
+            registry.push(type_id);
+            Some(registry.len() - 1)
+        }
+    }
+}
+
+//FNV-1a, unlike DefaultHasher its output does not change between Rust releases
+struct StateHasher(u64);
+
+impl Hasher for StateHasher {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+
+    fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+//indices of the registered types, ordered by their TypeId
+fn sorted_slots(registry: &[TypeId]) -> Vec<usize> {
+    let mut slots: Vec<_> = (0..registry.len())
+        .filter(|index| !is_free_slot(&registry[*index]))
+        .collect();
+    slots.sort_unstable_by_key(|index| registry[*index]);
+    slots
+}
+
+fn occupied_slots(registry: &[TypeId]) -> u64 {
+    registry
+        .iter()
+        .enumerate()
+        .filter(|(_, type_id)| !is_free_slot(type_id))
+        .fold(0, |mask, (index, _)| mask.with_bit(index))
+}
+
 #[derive(Clone, Copy, Debug, Default)]
 struct LookupEntry {
     index: Option<NonZeroU16>,
@@ -189,15 +388,130 @@ struct LookupEntry {
 }
 
 impl LookupEntry {
+    //marks an id held by reserve_id, no block can have this index
+    const RESERVED_BLOCK_INDEX: u16 = u16::MAX;
+
     fn new(block_index: u16, index: u16) -> Self {
         Self {
             index: unsafe { Some(NonZeroU16::new_unchecked(index + 1)) },
             block_index,
         }
     }
+
+    fn reserved() -> Self {
+        Self {
+            index: None,
+            block_index: Self::RESERVED_BLOCK_INDEX,
+        }
+    }
+
+    fn is_free(&self) -> bool {
+        self.index.is_none() && self.block_index != Self::RESERVED_BLOCK_INDEX
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct IdInUse;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Generation(u64);
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct OutOfBlocks;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct TypeInUse;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ValidationError<Id = GearId> {
+    BlockOverflow(u16),
+    MetadataLength(u16),
+    InvalidPointers(u16),
+    StaleLookup(Id),
+    DanglingLookup(Id),
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ComponentError {
+    NeedsDrop,
+    TooLarge,
+    BadAlign,
+    TooManyTypes,
+}
+
+fn check_component<T>() -> Result<(), ComponentError> {
+    if needs_drop::<T>() {
+        Err(ComponentError::NeedsDrop)
+    } else if size_of::<T>() > u16::MAX as usize {
+        Err(ComponentError::TooLarge)
+    } else if align_of::<T>() > u8::MAX as usize {
+        Err(ComponentError::BadAlign)
+    } else {
+        Ok(())
+    }
+}
+
+pub const fn is_valid_component<T>() -> bool {
+    !needs_drop::<T>() && size_of::<T>() <= u16::MAX as usize && align_of::<T>() <= u8::MAX as usize
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ArchetypeTooWide;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RawAddError {
+    InvalidGearId,
+    UnregisteredComponent,
+    SizeMismatch,
+    NotPlainData,
+}
+
+pub trait Mask: Copy {
+    fn contains(&self, other: &Self) -> bool;
+    fn intersects(&self, other: &Self) -> bool;
+    fn has_bit(&self, index: usize) -> bool;
+    fn with_bit(self, index: usize) -> Self;
+}
+
+impl Mask for u64 {
+    #[inline]
+    fn contains(&self, other: &Self) -> bool {
+        self & other == *other
+    }
+
+    #[inline]
+    fn intersects(&self, other: &Self) -> bool {
+        self & other != 0
+    }
+
+    #[inline]
+    fn has_bit(&self, index: usize) -> bool {
+        self & (1 << index as u64) != 0
+    }
+
+    #[inline]
+    fn with_bit(self, index: usize) -> Self {
+        self | 1 << index as u64
+    }
+}
+
+pub fn queries_conflict(
+    write_selector: u64,
+    read_selector: u64,
+    other_write_selector: u64,
+    other_read_selector: u64,
+) -> bool {
+    write_selector.intersects(&(other_write_selector | other_read_selector))
+        || other_write_selector.intersects(&read_selector)
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct QueryAccess {
+    pub reads: u64,
+    pub writes: u64,
 }
 
-#[derive(Copy, Clone, Eq, PartialEq, Debug)]
+#[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
 struct BlockMask {
     type_mask: u64,
     tag_mask: u64,
@@ -223,26 +537,298 @@ impl BlockMask {
     }
 }
 
-pub struct GearDataManager {
+/// Keeps a large component on the heap so that only a pointer is stored in the block.
+#[derive(Clone)]
+pub struct Boxed<T>(Box<T>);
+
+impl<T> Boxed<T> {
+    pub fn new(value: T) -> Self {
+        Self(Box::new(value))
+    }
+}
+
+impl<T> Deref for Boxed<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> DerefMut for Boxed<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+/// Keeps last frame's value readable while the next one is being written.
+/// Registered with `register_double_buffered` and flipped with `swap_buffers`.
+#[derive(Clone)]
+pub struct DoubleBuffered<T> {
+    front: T,
+    back: T,
+}
+
+impl<T: Clone> DoubleBuffered<T> {
+    pub fn new(value: T) -> Self {
+        Self {
+            front: value.clone(),
+            back: value,
+        }
+    }
+
+    pub fn front(&self) -> &T {
+        &self.front
+    }
+
+    pub fn back_mut(&mut self) -> &mut T {
+        &mut self.back
+    }
+
+    pub fn split(&mut self) -> (&T, &mut T) {
+        (&self.front, &mut self.back)
+    }
+}
+
+type DropFn = unsafe fn(*mut u8);
+type AnyFn = unsafe fn(&u8) -> &dyn Any;
+
+unsafe fn component_as_any<T: 'static>(first_byte: &u8) -> &dyn Any {
+    &*(first_byte as *const u8 as *const T)
+}
+
+/// Receives every component of every gear from `GearDataManager::visit_all`.
+/// The concrete type can be recovered with `downcast_ref`.
+pub trait ComponentVisitor<Id = GearId> {
+    fn visit(&mut self, gear_id: Id, component: ComponentId, value: &dyn Any);
+}
+
+unsafe fn drop_component<T>(ptr: *mut u8) {
+    drop_in_place(ptr as *mut T)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Stats {
+    pub count: usize,
+    pub min: f64,
+    pub max: f64,
+    pub mean: f64,
+}
+
+/// Owned, type-erased copy of a gear's components and tags.
+#[derive(Clone, Debug)]
+pub struct GearBundle {
+    components: Vec<(ComponentId, Vec<u8>)>,
+    tag_mask: u64,
+}
+
+/// Up to 64 boolean flags kept in a regular component, so that toggling a flag
+/// does not move the gear to another archetype the way tags do.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Flags(pub u64);
+
+fn flag_bit(flag: u8) -> u64 {
+    assert!(flag < 64, "Flag index out of range");
+    1 << flag
+}
+
+pub struct DetachedComponent<T> {
+    value: T,
+}
+
+impl<T> DetachedComponent<T> {
+    pub fn value(&self) -> &T {
+        &self.value
+    }
+}
+
+/// Refers to a component of a gear across relocations.
+/// The last known position is checked first and refreshed from the lookup table when stale.
+pub struct ComponentRef<T, Id = GearId> {
+    gear_id: Id,
+    type_index: usize,
+    location: Cell<(u16, u16)>,
+    phantom_type: PhantomData<T>,
+}
+
+impl<T: 'static, Id: GearIdType> ComponentRef<T, Id> {
+    pub fn gear_id(&self) -> Id {
+        self.gear_id
+    }
+
+    pub fn get<'a>(&self, data: &'a GearDataManager<Id>) -> Option<&'a T> {
+        //the type may have been unregistered since
+        if data.types.get(self.type_index) != Some(&TypeId::of::<T>()) {
+            return None;
+        }
+        let (block_index, index) = self.location.get();
+        let cached = data
+            .blocks
+            .get(block_index as usize)
+            .filter(|block| index < block.elements_count)
+            .filter(|block| block.gear_ids()[index as usize] == self.gear_id)
+            .is_some();
+        if !cached {
+            self.location.set(data.locate(self.gear_id)?);
+        }
+
+        let (block_index, index) = self.location.get();
+        let ptr = data.blocks[block_index as usize].component_blocks[self.type_index]?;
+        Some(unsafe { &*(ptr.as_ptr() as *const T).add(index as usize) })
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum ErrorPolicy {
+    Panic,
+    LogAndSkip(fn(&str)),
+}
+
+/// Provides the memory backing data blocks.
+pub trait BlockAllocator {
+    fn allocate(&mut self, layout: Layout) -> NonNull<u8>;
+
+    /// # Safety
+    /// `ptr` must have been returned by `allocate` of this allocator with the same `layout`
+    /// and must not be used afterwards.
+    unsafe fn release(&mut self, ptr: NonNull<u8>, layout: Layout);
+}
+
+struct GlobalBlockAllocator;
+
+impl BlockAllocator for GlobalBlockAllocator {
+    fn allocate(&mut self, layout: Layout) -> NonNull<u8> {
+        NonNull::new(unsafe { alloc(layout) }).unwrap_or_else(|| handle_alloc_error(layout))
+    }
+
+    unsafe fn release(&mut self, ptr: NonNull<u8>, layout: Layout) {
+        dealloc(ptr.as_ptr(), layout)
+    }
+}
+
+//the block memory is aligned to any gear id type
+fn block_layout() -> Layout {
+    Layout::new::<[u32; BLOCK_SIZE / size_of::<u32>()]>()
+}
+
+static NEXT_MANAGER_ID: AtomicU64 = AtomicU64::new(0);
+
+pub struct GearDataManager<Id: GearIdType = GearId> {
+    id: u64,
     types: Vec<TypeId>,
     tags: Vec<TypeId>,
-    blocks: Vec<DataBlock>,
+    blocks: Vec<DataBlock<Id>>,
     block_masks: Vec<BlockMask>,
     element_sizes: Box<[u16; 64]>,
     element_alignments: Box<[u8; 64]>,
+    type_names: Box<[&'static str; 64]>,
+    drop_fns: Box<[Option<DropFn>; 64]>,
+    any_fns: Box<[Option<AnyFn>; 64]>,
     lookup: Box<[LookupEntry]>,
+    query_cache: HashMap<(u64, u64), Vec<u16>>,
+    layout_version: u64,
+    open_blocks: HashMap<BlockMask, u16>,
+    recycling: HashMap<BlockMask, u16>,
+    pinned: HashSet<BlockMask>,
+    observed_transitions: HashSet<(BlockMask, BlockMask)>,
+    transition_log: Option<Vec<(Id, u64, u64)>>,
+    removal_journal: Option<Vec<Id>>,
+    error_policy: ErrorPolicy,
+    zero_on_add: bool,
+    block_allocator: Box<dyn BlockAllocator>,
+    generation: u64,
+    #[cfg(feature = "query-timings")]
+    query_timings: Vec<(u16, u64)>,
+}
+
+impl<Id: GearIdType> Drop for GearDataManager<Id> {
+    fn drop(&mut self) {
+        if self.drop_fns.iter().any(Option::is_some) {
+            for block_index in 0..self.blocks.len() {
+                for index in 0..self.blocks[block_index].elements_count {
+                    self.drop_components(block_index as u16, index);
+                }
+            }
+        }
+        for block in self.blocks.iter().filter(|block| !block.attached) {
+            unsafe { self.block_allocator.release(block.data, block_layout()) };
+        }
+    }
+}
+
+impl Default for GearDataManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl GearDataManager {
     pub fn new() -> Self {
+        Self::with_capacity(u16::MAX)
+    }
+
+    /// Sizes the lookup table for gear ids up to `max_gears`.
+    /// Adding a gear with a higher id grows the table.
+    pub fn with_capacity(max_gears: u16) -> Self {
+        Self::with_lookup_capacity(usize::from(max_gears))
+    }
+}
+
+impl<Id: GearIdType> GearDataManager<Id> {
+    /// Like `with_capacity`, for a manager keyed by any `GearIdType`.
+    pub fn with_lookup_capacity(max_gears: usize) -> Self {
         Self {
+            id: NEXT_MANAGER_ID.fetch_add(1, Ordering::Relaxed),
             types: Vec::with_capacity(64),
             tags: Vec::with_capacity(64),
             blocks: vec![],
             block_masks: vec![],
             element_sizes: Box::new([0; 64]),
             element_alignments: Box::new([0; 64]),
-            lookup: vec![LookupEntry::default(); u16::max_value() as usize].into_boxed_slice(),
+            type_names: Box::new([""; 64]),
+            drop_fns: Box::new([None; 64]),
+            any_fns: Box::new([None; 64]),
+            lookup: vec![LookupEntry::default(); max_gears].into_boxed_slice(),
+            query_cache: HashMap::new(),
+            layout_version: 0,
+            open_blocks: HashMap::new(),
+            recycling: HashMap::new(),
+            pinned: HashSet::new(),
+            observed_transitions: HashSet::new(),
+            transition_log: None,
+            removal_journal: None,
+            error_policy: ErrorPolicy::Panic,
+            zero_on_add: false,
+            block_allocator: Box::new(GlobalBlockAllocator),
+            generation: 0,
+            #[cfg(feature = "query-timings")]
+            query_timings: vec![],
+        }
+    }
+
+    pub fn set_error_policy(&mut self, policy: ErrorPolicy) {
+        self.error_policy = policy;
+    }
+
+    /// Zeroes the slot of every added component before writing the value into it,
+    /// so that padding bytes do not keep data of previously removed gears.
+    pub fn set_zero_on_add(&mut self, enabled: bool) {
+        self.zero_on_add = enabled;
+    }
+
+    /// Must be called before the first block is allocated.
+    pub fn set_block_allocator(&mut self, allocator: Box<dyn BlockAllocator>) {
+        if self.blocks.is_empty() {
+            self.block_allocator = allocator;
+        } else {
+            self.report_misuse("Cannot replace the allocator of existing blocks")
+        }
+    }
+
+    fn report_misuse(&self, message: &str) {
+        match self.error_policy {
+            ErrorPolicy::Panic => panic!("{}", message),
+            ErrorPolicy::LogAndSkip(log) => log(message),
         }
     }
 
@@ -258,47 +844,324 @@ impl GearDataManager {
         self.tags.iter().position(|id| *id == type_id)
     }
 
-    fn move_between_blocks(
-        &mut self,
-        src_block_index: u16,
-        src_index: u16,
-        dest_block_index: u16,
-    ) -> u16 {
-        debug_assert!(src_block_index != dest_block_index);
-        let src_mask = self.block_masks[src_block_index as usize];
-        let dest_mask = self.block_masks[dest_block_index as usize];
-        debug_assert!(src_mask.type_mask & dest_mask.type_mask == src_mask.type_mask);
+    pub fn archetype_count(&self) -> usize {
+        self.block_masks.iter().collect::<HashSet<_>>().len()
+    }
 
-        let src_block = &self.blocks[src_block_index as usize];
-        let dest_block = &self.blocks[dest_block_index as usize];
-        debug_assert!(src_index < src_block.elements_count);
-        debug_assert!(!dest_block.is_full());
+    /// Data types indexed by `ComponentId`, which is the canonical component order.
+    /// The slot of an unregistered type holds a placeholder until another type reuses it.
+    pub fn registered_types(&self) -> &[TypeId] {
+        &self.types
+    }
 
-        let dest_index = dest_block.elements_count;
-        for i in 0..self.types.len() {
-            if src_mask.type_mask & (1 << i as u64) != 0 {
-                let size = self.element_sizes[i];
-                let src_ptr = src_block.component_blocks[i].unwrap().as_ptr();
-                let dest_ptr = dest_block.component_blocks[i].unwrap().as_ptr();
-                unsafe {
-                    copy_nonoverlapping(
-                        src_ptr.add((src_index * size) as usize),
-                        dest_ptr.add((dest_index * size) as usize),
-                        size as usize,
-                    );
-                    if src_index < src_block.elements_count - 1 {
-                        copy_nonoverlapping(
-                            src_ptr.add((size * (src_block.elements_count - 1)) as usize),
-                            src_ptr.add((size * src_index) as usize),
-                            size as usize,
-                        );
-                    }
-                }
+    pub fn for_each_component_type<F: FnMut(ComponentId, TypeId, u16)>(&self, mut f: F) {
+        for (index, type_id) in self.types.iter().enumerate() {
+            if !is_free_slot(type_id) {
+                f(index as ComponentId, *type_id, self.element_sizes[index]);
             }
         }
+    }
 
-        let src_block = &mut self.blocks[src_block_index as usize];
-        let gear_id = src_block.gear_ids()[src_index as usize];
+    fn is_registered(&self, component: ComponentId) -> bool {
+        self.types
+            .get(component as usize)
+            .is_some_and(|type_id| !is_free_slot(type_id))
+    }
+
+    pub fn component_size(&self, type_id: TypeId) -> Option<u16> {
+        self.types
+            .iter()
+            .position(|id| *id == type_id)
+            .map(|i| self.element_sizes[i])
+    }
+
+    pub fn mask_to_type_names(&self, mask: u64) -> Vec<&'static str> {
+        (0..self.types.len())
+            .filter(|i| mask.has_bit(*i))
+            .map(|i| self.type_names[i])
+            .collect()
+    }
+
+    pub fn component_id<T: 'static>(&self) -> Option<ComponentId> {
+        self.get_type_index::<T>().map(|i| i as ComponentId)
+    }
+
+    pub fn contains<T: 'static>(&self, gear_id: Id) -> bool {
+        let entry = self.lookup_entry(gear_id);
+        match (entry.index, self.get_type_index::<T>()) {
+            (Some(_), Some(type_index)) => self.block_masks[entry.block_index as usize]
+                .type_mask
+                .has_bit(type_index),
+            _ => false,
+        }
+    }
+
+    pub fn component_ids_of(&self, gear_id: Id) -> Vec<ComponentId> {
+        let entry = self.lookup_entry(gear_id);
+        if entry.index.is_none() {
+            return vec![];
+        }
+        let type_mask = self.block_masks[entry.block_index as usize].type_mask;
+        (0..self.types.len())
+            .filter(|i| type_mask.has_bit(*i))
+            .map(|i| i as ComponentId)
+            .collect()
+    }
+
+    /// Returns the block and element index of the gear.
+    /// The element index changes when another gear is removed from the same block.
+    pub fn locate(&self, gear_id: Id) -> Option<(u16, u16)> {
+        let entry = self.lookup_entry(gear_id);
+        entry
+            .index
+            .map(|index| (entry.block_index, index.get() - 1))
+    }
+
+    #[inline]
+    fn lookup_entry(&self, gear_id: Id) -> LookupEntry {
+        #[cfg(feature = "strict")]
+        if gear_id.to_usize() > self.lookup.len() {
+            panic!(
+                "Gear id {} is out of the lookup range 1..={}",
+                gear_id,
+                self.lookup.len()
+            )
+        }
+        self.lookup
+            .get(gear_id.to_usize() - 1)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn ensure_lookup(&mut self, gear_id: Id) {
+        let size = gear_id.to_usize();
+        if size > self.lookup.len() {
+            let mut lookup = self.lookup.to_vec();
+            lookup.resize(
+                max(size, lookup.len() * 2).min(Id::MAX),
+                LookupEntry::default(),
+            );
+            self.lookup = lookup.into_boxed_slice();
+        }
+    }
+
+    fn get_component_ptr(&self, gear_id: Id, type_index: usize) -> Option<*mut u8> {
+        let entry = self.lookup_entry(gear_id);
+        entry.index.and_then(|index| {
+            let block = &self.blocks[entry.block_index as usize];
+            block.component_blocks[type_index].map(|ptr| unsafe {
+                ptr.as_ptr()
+                    .add(self.element_sizes[type_index] as usize * (index.get() - 1) as usize)
+            })
+        })
+    }
+
+    /// Bumps the version of the gear, see `version_of`.
+    pub fn get_mut<T: 'static>(&mut self, gear_id: Id) -> Option<&mut T> {
+        let type_index = self.get_type_index::<T>()?;
+        let ptr = self.get_component_ptr(gear_id, type_index)?;
+        let entry = self.lookup_entry(gear_id);
+        let version = &mut self.blocks[entry.block_index as usize].versions
+            [entry.index.unwrap().get() as usize - 1];
+        *version = version.wrapping_add(1);
+        Some(unsafe { &mut *(ptr as *mut T) })
+    }
+
+    pub fn component_ref<T: 'static>(&self, gear_id: Id) -> Option<ComponentRef<T, Id>> {
+        let type_index = self.get_type_index::<T>()?;
+        let location = self.locate(gear_id)?;
+        Some(ComponentRef {
+            gear_id,
+            type_index,
+            location: Cell::new(location),
+            phantom_type: PhantomData,
+        })
+    }
+
+    pub fn replace<T: 'static>(&mut self, gear_id: Id, value: T) -> Option<T> {
+        self.get_mut(gear_id)
+            .map(|component| std::mem::replace(component, value))
+    }
+
+    /// A token that changes whenever the gear is accessed through `get_mut` or `replace`.
+    /// Mutation during iteration does not change it.
+    pub fn version_of<T: 'static>(&self, gear_id: Id) -> Option<u32> {
+        let type_index = self.get_type_index::<T>()?;
+        let entry = self.lookup_entry(gear_id);
+        let index = entry.index?.get() as usize - 1;
+        if self.block_masks[entry.block_index as usize]
+            .type_mask
+            .has_bit(type_index)
+        {
+            Some(self.blocks[entry.block_index as usize].versions[index])
+        } else {
+            None
+        }
+    }
+
+    pub fn component_bytes(&self, gear_id: Id, component: ComponentId) -> Option<&[u8]> {
+        if !self.is_registered(component) {
+            return None;
+        }
+        self.get_component_ptr(gear_id, component as usize)
+            .map(|ptr| unsafe {
+                slice::from_raw_parts(ptr, self.element_sizes[component as usize] as usize)
+            })
+    }
+
+    pub fn component_byte_slice(&self, block_index: u16, component: ComponentId) -> &[u8] {
+        let block = &self.blocks[block_index as usize];
+        match block
+            .component_blocks
+            .get(component as usize)
+            .cloned()
+            .flatten()
+        {
+            Some(ptr) => unsafe {
+                slice::from_raw_parts(
+                    ptr.as_ptr(),
+                    self.element_sizes[component as usize] as usize * block.elements_count as usize,
+                )
+            },
+            None => &[],
+        }
+    }
+
+    fn get_block_mask<T: TypeTuple>(&self) -> Option<BlockMask> {
+        let mut arg_types = Vec::with_capacity(64);
+        T::get_types(&mut arg_types);
+        let mut mask = BlockMask::new(0, 0);
+
+        for type_id in arg_types {
+            if let Some(i) = self.types.iter().position(|t| *t == type_id) {
+                mask = mask.with_type(1 << i as u64);
+            } else if let Some(i) = self.tags.iter().position(|t| *t == type_id) {
+                mask = mask.with_tag(1 << i as u64);
+            } else {
+                self.report_misuse(&format!("Unregistered type {}", type_name::<T>()));
+                return None;
+            }
+        }
+        Some(mask)
+    }
+
+    fn get_archetype_mask<T: TypeTuple>(&self) -> Option<BlockMask> {
+        let mask = self.get_block_mask::<T>()?;
+        if mask.type_mask == 0 {
+            self.report_misuse("Archetype has no data components");
+            None
+        } else {
+            Some(mask)
+        }
+    }
+
+    #[inline]
+    fn log_transition(&mut self, gear_id: Id, old_mask: u64, new_mask: u64) {
+        if let Some(log) = &mut self.transition_log {
+            log.push((gear_id, old_mask, new_mask));
+        }
+    }
+
+    #[inline]
+    fn log_removal(&mut self, gear_id: Id) {
+        if let Some(journal) = &mut self.removal_journal {
+            journal.push(gear_id);
+        }
+    }
+
+    pub fn set_journal(&mut self, enabled: bool) {
+        if enabled != self.removal_journal.is_some() {
+            self.removal_journal = if enabled { Some(vec![]) } else { None };
+        }
+    }
+
+    /// Gears that lost all of their data since the last call, in removal order.
+    pub fn drain_removed(&mut self) -> Vec<Id> {
+        self.removal_journal
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    pub fn set_track_transitions(&mut self, enabled: bool) {
+        if enabled != self.transition_log.is_some() {
+            self.transition_log = if enabled { Some(vec![]) } else { None };
+        }
+    }
+
+    /// Masks are type masks, so a tag-only change is logged with equal old and new masks.
+    pub fn drain_transitions(&mut self) -> Vec<(Id, u64, u64)> {
+        self.transition_log
+            .as_mut()
+            .map(std::mem::take)
+            .unwrap_or_default()
+    }
+
+    fn move_between_blocks(
+        &mut self,
+        src_block_index: u16,
+        src_index: u16,
+        dest_block_index: u16,
+    ) -> u16 {
+        debug_assert!(src_block_index != dest_block_index);
+        let src_mask = self.block_masks[src_block_index as usize];
+        let dest_mask = self.block_masks[dest_block_index as usize];
+        debug_assert!(
+            src_mask.type_mask.contains(&dest_mask.type_mask)
+                || dest_mask.type_mask.contains(&src_mask.type_mask)
+        );
+        if src_mask != dest_mask {
+            self.observed_transitions.insert((src_mask, dest_mask));
+            self.evict_recycled(dest_block_index);
+            let gear_id = self.blocks[src_block_index as usize].gear_ids()[src_index as usize];
+            self.log_transition(gear_id, src_mask.type_mask, dest_mask.type_mask);
+        }
+
+        let src_block = &self.blocks[src_block_index as usize];
+        let dest_block = &self.blocks[dest_block_index as usize];
+        debug_assert!(src_index < src_block.elements_count);
+        assert!(!dest_block.is_full(), "Destination block is full");
+
+        let dest_index = dest_block.elements_count;
+        let move_component = |i: usize| {
+            let size = self.element_sizes[i] as usize;
+            let src_ptr = src_block.component_blocks[i].unwrap().as_ptr();
+            unsafe {
+                if let Some(dest_ptr) = dest_block.component_blocks[i] {
+                    copy_nonoverlapping(
+                        src_ptr.add(src_index as usize * size),
+                        dest_ptr.as_ptr().add(dest_index as usize * size),
+                        size,
+                    );
+                } else if let Some(drop_fn) = self.drop_fns[i] {
+                    drop_fn(src_ptr.add(src_index as usize * size));
+                }
+                if src_index < src_block.elements_count - 1 {
+                    copy_nonoverlapping(
+                        src_ptr.add(size * (src_block.elements_count as usize - 1)),
+                        src_ptr.add(size * src_index as usize),
+                        size,
+                    );
+                }
+            }
+        };
+
+        if src_mask.type_mask.count_ones() == 1 {
+            //adding the second component is the most common transition
+            move_component(src_mask.type_mask.trailing_zeros() as usize);
+        } else {
+            (0..self.types.len())
+                .filter(|i| src_mask.type_mask.has_bit(*i))
+                .for_each(move_component);
+        }
+
+        let src_block = &mut self.blocks[src_block_index as usize];
+        let gear_id = src_block.gear_ids()[src_index as usize];
+        let disabled = src_block
+            .disabled_components
+            .swap_remove(src_index as usize);
+        let generation = src_block.generations.swap_remove(src_index as usize);
+        let version = src_block.versions.swap_remove(src_index as usize);
 
         if src_index < src_block.elements_count - 1 {
             let relocated_index = src_block.elements_count as usize - 1;
@@ -306,8 +1169,7 @@ impl GearDataManager {
             let relocated_id = gear_ids[relocated_index];
 
             gear_ids[src_index as usize] = relocated_id;
-            self.lookup[relocated_id.get() as usize - 1] =
-                LookupEntry::new(src_block_index, src_index);
+            self.lookup[relocated_id.to_usize() - 1] = LookupEntry::new(src_block_index, src_index);
         }
         src_block.elements_count -= 1;
 
@@ -315,111 +1177,313 @@ impl GearDataManager {
         let dest_index = dest_block.elements_count;
 
         dest_block.gear_ids_mut()[dest_index as usize] = gear_id;
-        self.lookup[gear_id.get() as usize - 1] = LookupEntry::new(dest_block_index, dest_index);
+        dest_block
+            .disabled_components
+            .push(disabled & dest_mask.type_mask);
+        dest_block.generations.push(generation);
+        dest_block.versions.push(version);
+        self.lookup[gear_id.to_usize() - 1] = LookupEntry::new(dest_block_index, dest_index);
         dest_block.elements_count += 1;
         dest_block.elements_count - 1
     }
 
-    fn add_to_block<T: Clone>(&mut self, gear_id: GearId, block_index: u16, value: &T) {
-        debug_assert!(
-            self.block_masks[block_index as usize]
-                .type_mask
-                .count_ones()
-                == 1
-        );
+    fn add_to_block<T>(&mut self, gear_id: Id, block_index: u16, value: T) {
+        let type_mask = self.block_masks[block_index as usize].type_mask;
+        debug_assert!(type_mask.count_ones() == 1);
+        let type_index = type_mask.trailing_zeros() as usize;
 
-        let block = &mut self.blocks[block_index as usize];
-        debug_assert!(block.elements_count < block.max_elements);
+        let index = self.append_gear(gear_id, block_index);
+        self.write_component(block_index, index, type_index, value);
+    }
 
-        unsafe {
-            *(block.component_blocks[0].unwrap().as_ptr() as *mut T)
-                .add(block.elements_count as usize) = value.clone();
-        };
+    fn append_gear(&mut self, gear_id: Id, block_index: u16) -> u16 {
+        self.evict_recycled(block_index);
+        let type_mask = self.block_masks[block_index as usize].type_mask;
+        let block = &mut self.blocks[block_index as usize];
+        assert!(!block.is_full(), "Destination block is full");
 
         let index = block.elements_count;
-        self.lookup[gear_id.get() as usize - 1] = LookupEntry::new(block_index, index);
+        self.lookup[gear_id.to_usize() - 1] = LookupEntry::new(block_index, index);
         block.gear_ids_mut()[index as usize] = gear_id;
+        block.disabled_components.push(0);
+        block.generations.push(self.generation);
+        block.versions.push(0);
         block.elements_count += 1;
+        self.log_transition(gear_id, 0, type_mask);
+        index
+    }
+
+    fn evict_recycled(&mut self, block_index: u16) {
+        let mask = self.block_masks[block_index as usize];
+        if let Some(&capacity) = self.recycling.get(&mask) {
+            if self.blocks[block_index as usize].elements_count >= capacity {
+                self.remove_ordered_from_block(block_index, 0);
+            }
+        }
+    }
+
+    fn drop_components(&mut self, block_index: u16, index: u16) {
+        let block = &self.blocks[block_index as usize];
+        for (i, drop_fn) in self.drop_fns[0..self.types.len()].iter().enumerate() {
+            if let (Some(drop_fn), Some(ptr)) = (drop_fn, block.component_blocks[i]) {
+                let size = self.element_sizes[i] as usize;
+                unsafe { drop_fn(ptr.as_ptr().add(size * index as usize)) };
+            }
+        }
     }
 
     fn remove_from_block(&mut self, block_index: u16, index: u16) {
+        let gear_id = self.blocks[block_index as usize].gear_ids()[index as usize];
+        let type_mask = self.block_masks[block_index as usize].type_mask;
+        self.log_transition(gear_id, type_mask, 0);
+        self.log_removal(gear_id);
+
+        self.drop_components(block_index, index);
         let block = &mut self.blocks[block_index as usize];
         debug_assert!(index < block.elements_count);
 
-        for (i, size) in self.element_sizes.iter().cloned().enumerate() {
+        for (i, size) in self.element_sizes[0..self.types.len()]
+            .iter()
+            .map(|size| *size as usize)
+            .enumerate()
+        {
             if index < block.elements_count - 1 {
                 if let Some(ptr) = block.component_blocks[i] {
                     unsafe {
                         copy_nonoverlapping(
-                            ptr.as_ptr()
-                                .add((size * (block.elements_count - 1)) as usize),
-                            ptr.as_ptr().add((size * index) as usize),
-                            size as usize,
+                            ptr.as_ptr().add(size * (block.elements_count as usize - 1)),
+                            ptr.as_ptr().add(size * index as usize),
+                            size,
                         );
                     }
                 }
             }
         }
 
-        self.lookup[block.gear_ids()[index as usize].get() as usize - 1] = LookupEntry::default();
+        self.lookup[block.gear_ids()[index as usize].to_usize() - 1] = LookupEntry::default();
         if index < block.elements_count - 1 {
             let relocated_index = block.elements_count as usize - 1;
             let gear_ids = block.gear_ids_mut();
 
             gear_ids[index as usize] = gear_ids[relocated_index];
-            self.lookup[gear_ids[relocated_index].get() as usize - 1] =
+            self.lookup[gear_ids[relocated_index].to_usize() - 1] =
                 LookupEntry::new(block_index, index);
         }
+        block.disabled_components.swap_remove(index as usize);
+        block.generations.swap_remove(index as usize);
+        block.versions.swap_remove(index as usize);
         block.elements_count -= 1;
     }
 
-    fn write_component<T: Clone>(
-        &mut self,
-        block_index: u16,
-        index: u16,
-        type_index: usize,
-        value: &T,
-    ) {
+    fn remove_ordered_from_block(&mut self, block_index: u16, index: u16) {
+        let gear_id = self.blocks[block_index as usize].gear_ids()[index as usize];
+        let type_mask = self.block_masks[block_index as usize].type_mask;
+        self.log_transition(gear_id, type_mask, 0);
+        self.log_removal(gear_id);
+
+        self.drop_components(block_index, index);
+        let block = &mut self.blocks[block_index as usize];
+        debug_assert!(index < block.elements_count);
+        let tail_count = (block.elements_count - index - 1) as usize;
+
+        for (i, size) in self.element_sizes[0..self.types.len()]
+            .iter()
+            .map(|size| *size as usize)
+            .enumerate()
+        {
+            if let Some(ptr) = block.component_blocks[i] {
+                unsafe {
+                    copy(
+                        ptr.as_ptr().add(size * (index as usize + 1)),
+                        ptr.as_ptr().add(size * index as usize),
+                        size * tail_count,
+                    );
+                }
+            }
+        }
+
+        self.lookup[gear_id.to_usize() - 1] = LookupEntry::default();
+        let count = block.elements_count as usize;
+        let gear_ids = block.gear_ids_mut();
+        gear_ids.copy_within(index as usize + 1..count, index as usize);
+        for (i, gear_id) in gear_ids[index as usize..count - 1].iter().enumerate() {
+            self.lookup[gear_id.to_usize() - 1] = LookupEntry::new(block_index, index + i as u16);
+        }
+        block.disabled_components.remove(index as usize);
+        block.generations.remove(index as usize);
+        block.versions.remove(index as usize);
+        block.elements_count -= 1;
+    }
+
+    fn write_component<T>(&mut self, block_index: u16, index: u16, type_index: usize, value: T) {
         debug_assert!(type_index < self.types.len());
         let block = &mut self.blocks[block_index as usize];
         debug_assert!(index < block.elements_count);
 
         unsafe {
-            *(block.component_blocks[type_index].unwrap().as_ptr() as *mut T).add(index as usize) =
-                value.clone();
+            let ptr = (block.component_blocks[type_index].unwrap().as_ptr() as *mut T)
+                .add(index as usize);
+            if self.zero_on_add {
+                //a typed write may skip the padding, so the value is copied bytewise
+                ptr.write_bytes(0, 1);
+                copy_nonoverlapping(
+                    &value as *const T as *const u8,
+                    ptr as *mut u8,
+                    size_of::<T>(),
+                );
+                forget(value);
+            } else {
+                ptr.write(value);
+            }
         };
     }
 
-    #[inline]
-    fn ensure_block(&mut self, mask: BlockMask) -> u16 {
-        if let Some(index) = self
+    fn find_block(&mut self, mask: BlockMask) -> Option<u16> {
+        if let Some(index) = self.open_blocks.get(&mask) {
+            if !self.blocks[*index as usize].is_full() {
+                return Some(*index);
+            }
+        }
+
+        let index = self
             .block_masks
             .iter()
             .enumerate()
             .position(|(i, m)| *m == mask && !self.blocks[i].is_full())
-        {
-            index as u16
-        } else {
-            self.blocks.push(DataBlock::new(
-                mask.type_mask,
-                &self.element_sizes[0..self.types.len()],
-                &self.element_alignments[0..self.types.len()],
-            ));
-            self.block_masks.push(mask);
-            (self.blocks.len() - 1) as u16
+            .map(|index| index as u16);
+        match index {
+            Some(index) => self.open_blocks.insert(mask, index),
+            None => self.open_blocks.remove(&mask),
+        };
+        index
+    }
+
+    #[inline]
+    fn ensure_block(&mut self, mask: BlockMask) -> u16 {
+        match self.find_block(mask) {
+            Some(index) => index,
+            None => {
+                if self.mask_capacity(mask.type_mask) == 0 {
+                    panic!("Archetype components do not fit in a block")
+                }
+                self.push_block(mask)
+            }
+        }
+    }
+
+    fn push_block(&mut self, mask: BlockMask) -> u16 {
+        let data = self.block_allocator.allocate(block_layout());
+        let block = self.new_block(data, mask);
+        self.insert_block(block, mask)
+    }
+
+    fn new_block(&self, data: NonNull<u8>, mask: BlockMask) -> DataBlock<Id> {
+        DataBlock::new(
+            data,
+            mask.type_mask,
+            &self.element_sizes[0..self.types.len()],
+            &self.element_alignments[0..self.types.len()],
+        )
+    }
+
+    fn insert_block(&mut self, block: DataBlock<Id>, mask: BlockMask) -> u16 {
+        self.blocks.push(block);
+        self.block_masks.push(mask);
+        self.invalidate_queries();
+        let index = (self.blocks.len() - 1) as u16;
+        self.open_blocks.insert(mask, index);
+        index
+    }
+
+    fn mask_capacity(&self, type_mask: u64) -> u16 {
+        block_capacity::<Id>(
+            type_mask,
+            &self.element_sizes[0..self.types.len()],
+            &self.element_alignments[0..self.types.len()],
+        )
+    }
+
+    /// Number of gears of the archetype that fit in one block.
+    /// An archetype with unregistered types is reported and treated as too wide.
+    pub fn archetype_capacity<T: TypeTuple>(&self) -> Result<u16, ArchetypeTooWide> {
+        match self.get_block_mask::<T>() {
+            Some(mask) => match self.mask_capacity(mask.type_mask) {
+                0 => Err(ArchetypeTooWide),
+                capacity => Ok(capacity),
+            },
+            None => Err(ArchetypeTooWide),
+        }
+    }
+
+    pub fn ensure_archetype<T: TypeTuple>(&mut self) -> Option<u16> {
+        let mask = self.get_archetype_mask::<T>()?;
+        Some(self.ensure_block(mask))
+    }
+
+    /// Makes sure the next `count` gears added to the archetype land in the same block.
+    /// Returns `None` if `count` exceeds the block capacity of the archetype.
+    pub fn reserve_contiguous<T: TypeTuple>(&mut self, count: u16) -> Option<u16> {
+        let mask = self.get_archetype_mask::<T>()?;
+        let block_index = self.ensure_block(mask) as usize;
+        let block = &self.blocks[block_index];
+        if block.max_elements < count {
+            return None;
+        }
+
+        if block.max_elements - block.elements_count < count {
+            //the new block becomes the open block, so ensure_block picks it first
+            return Some(self.push_block(mask));
+        }
+        Some(block_index as u16)
+    }
+
+    /// Keeps the gears of the archetype in place during `consolidate` and `shrink_to_fit`
+    /// so that pointers into its blocks stay valid.
+    /// Freeing empty blocks in front of a pinned block still shifts its index.
+    pub fn pin_archetype<T: TypeTuple>(&mut self) {
+        if let Some(mask) = self.get_block_mask::<T>() {
+            self.pinned.insert(mask);
+        }
+    }
+
+    pub fn unpin_archetype<T: TypeTuple>(&mut self) {
+        if let Some(mask) = self.get_block_mask::<T>() {
+            self.pinned.remove(&mask);
+        }
+    }
+
+    /// Turns the archetype into a ring buffer of at most `capacity` gears:
+    /// adding to a full archetype evicts its oldest gear instead of allocating.
+    /// The capacity is limited to one less than the block size so that the
+    /// archetype always stays in a single block, a capacity of zero is reported.
+    /// Unordered removals from the archetype disturb the eviction order.
+    pub fn set_recycling<T: TypeTuple>(&mut self, capacity: u16) {
+        let mask = match self.get_archetype_mask::<T>() {
+            Some(mask) => mask,
+            None => return,
+        };
+        let block_index = self.ensure_block(mask) as usize;
+        match capacity.min(self.blocks[block_index].max_elements - 1) {
+            0 => self.report_misuse("Recycling capacity must be at least 1"),
+            capacity => {
+                self.recycling.insert(mask, capacity);
+            }
         }
     }
 
-    pub fn add<T: Clone + 'static>(&mut self, gear_id: GearId, value: &T) {
+    pub fn add<T: Clone + 'static>(&mut self, gear_id: Id, value: &T) {
         if let Some(type_index) = self.get_type_index::<T>() {
+            self.ensure_lookup(gear_id);
             let type_bit = 1 << type_index as u64;
-            let entry = self.lookup[gear_id.get() as usize - 1];
+            let entry = self.lookup_entry(gear_id);
 
             if let Some(index) = entry.index {
                 let mask = self.block_masks[entry.block_index as usize];
                 let new_mask = mask.with_type(type_bit);
 
                 if new_mask != mask {
+                    //clone before moving so that a panicking clone leaves the gear intact
+                    let value = value.clone();
                     let dest_block_index = self.ensure_block(new_mask);
                     let dest_index = self.move_between_blocks(
                         entry.block_index,
@@ -430,232 +1494,5038 @@ impl GearDataManager {
                 }
             } else {
                 let dest_block_index = self.ensure_block(BlockMask::new(type_bit, 0));
-                self.add_to_block(gear_id, dest_block_index, value);
+                self.add_to_block(gear_id, dest_block_index, value.clone());
             }
         } else {
-            panic!("Unregistered type")
+            self.report_misuse(&format!("Unregistered type {}", type_name::<T>()))
         }
     }
 
-    pub fn add_tag<T: 'static>(&mut self, gear_id: GearId) {
-        if let Some(tag_index) = self.get_tag_index::<T>() {
-            let tag_bit = 1 << tag_index as u64;
-            let entry = self.lookup[gear_id.get() as usize - 1];
+    pub fn upsert<T: Clone + 'static, F: FnOnce(&mut T)>(
+        &mut self,
+        gear_id: Id,
+        default: &T,
+        modify: F,
+    ) {
+        match self
+            .get_type_index::<T>()
+            .and_then(|type_index| self.get_component_ptr(gear_id, type_index))
+        {
+            Some(ptr) => modify(unsafe { &mut *(ptr as *mut T) }),
+            None => self.add(gear_id, default),
+        }
+    }
 
-            if let Some(index) = entry.index {
-                let mask = self.block_masks[entry.block_index as usize];
-                let new_mask = mask.with_tag(tag_bit);
+    /// Like `add`, but fails instead of allocating a new block.
+    pub fn try_add_no_alloc<T: Clone + 'static>(
+        &mut self,
+        gear_id: Id,
+        value: &T,
+    ) -> Result<(), OutOfBlocks> {
+        let type_index = match self.get_type_index::<T>() {
+            Some(index) => index,
+            None => {
+                self.report_misuse(&format!("Unregistered type {}", type_name::<T>()));
+                return Ok(());
+            }
+        };
+        let type_bit = 1 << type_index as u64;
+        self.ensure_lookup(gear_id);
+        let entry = self.lookup_entry(gear_id);
 
-                if new_mask != mask {
-                    let dest_block_index = self.ensure_block(new_mask);
+        if let Some(index) = entry.index {
+            let mask = self.block_masks[entry.block_index as usize];
+            let new_mask = mask.with_type(type_bit);
+
+            if new_mask != mask {
+                let dest_block_index = self.find_block(new_mask).ok_or(OutOfBlocks)?;
+                let value = value.clone();
+                let dest_index =
                     self.move_between_blocks(entry.block_index, index.get() - 1, dest_block_index);
-                }
-            } else {
-                panic!("Cannot tag a gear with no data")
+                self.write_component(dest_block_index, dest_index, type_index, value);
             }
         } else {
-            panic!("Unregistered tag")
+            let dest_block_index = self
+                .find_block(BlockMask::new(type_bit, 0))
+                .ok_or(OutOfBlocks)?;
+            self.add_to_block(gear_id, dest_block_index, value.clone());
         }
+        Ok(())
     }
 
-    pub fn remove<T: 'static>(&mut self, gear_id: GearId) {
-        if let Some(type_index) = self.get_type_index::<T>() {
-            let entry = self.lookup[gear_id.get() as usize - 1];
-            if let Some(index) = entry.index {
-                let mut dest_mask = self.block_masks[entry.block_index as usize];
-                dest_mask.type_mask &= !(1 << type_index as u64);
+    /// Untyped counterpart of `add` for callers that only have a raw gear id and bytes.
+    /// Overwrites the component if the gear already has it.
+    pub fn add_raw(
+        &mut self,
+        gear_id: Id::Raw,
+        component: ComponentId,
+        bytes: &[u8],
+    ) -> Result<(), RawAddError> {
+        let gear_id = Id::new(gear_id).ok_or(RawAddError::InvalidGearId)?;
+        let type_index = component as usize;
+        if !self.is_registered(component) {
+            return Err(RawAddError::UnregisteredComponent);
+        }
+        if self.drop_fns[type_index].is_some() {
+            return Err(RawAddError::NotPlainData);
+        }
+        let size = self.element_sizes[type_index] as usize;
+        if bytes.len() != size {
+            return Err(RawAddError::SizeMismatch);
+        }
 
-                if dest_mask.type_mask == 0 {
-                    self.remove_from_block(entry.block_index, index.get() - 1);
-                } else {
-                    let dest_block_index = self.ensure_block(dest_mask);
+        let type_bit = 1 << type_index as u64;
+        self.ensure_lookup(gear_id);
+        let entry = self.lookup_entry(gear_id);
+        let (block_index, index) = if let Some(index) = entry.index {
+            let mask = self.block_masks[entry.block_index as usize];
+            let new_mask = mask.with_type(type_bit);
+
+            if new_mask == mask {
+                (entry.block_index, index.get() - 1)
+            } else {
+                let dest_block_index = self.ensure_block(new_mask);
+                let dest_index =
                     self.move_between_blocks(entry.block_index, index.get() - 1, dest_block_index);
-                }
+                (dest_block_index, dest_index)
             }
         } else {
-            panic!("Unregistered type")
+            let dest_block_index = self.ensure_block(BlockMask::new(type_bit, 0));
+            (
+                dest_block_index,
+                self.append_gear(gear_id, dest_block_index),
+            )
+        };
+
+        let block = &self.blocks[block_index as usize];
+        unsafe {
+            copy_nonoverlapping(
+                bytes.as_ptr(),
+                block.component_blocks[type_index]
+                    .unwrap()
+                    .as_ptr()
+                    .add(size * index as usize),
+                size,
+            );
+        }
+        Ok(())
+    }
+
+    /// Gears holding `Boxed` components cannot be extracted.
+    pub fn extract(&self, gear_id: Id) -> Option<GearBundle> {
+        let entry = self.lookup_entry(gear_id);
+        let index = entry.index?.get() as usize - 1;
+        let mask = self.block_masks[entry.block_index as usize];
+        let block = &self.blocks[entry.block_index as usize];
+        let mut components = vec![];
+
+        for type_index in 0..self.types.len() {
+            if mask.type_mask.has_bit(type_index) {
+                if self.drop_fns[type_index].is_some() {
+                    return None;
+                }
+                let size = self.element_sizes[type_index] as usize;
+                let bytes = unsafe {
+                    slice::from_raw_parts(
+                        block.component_blocks[type_index]
+                            .unwrap()
+                            .as_ptr()
+                            .add(size * index),
+                        size,
+                    )
+                };
+                components.push((type_index as ComponentId, bytes.to_vec()));
+            }
         }
+
+        Some(GearBundle {
+            components,
+            tag_mask: mask.tag_mask,
+        })
     }
 
-    pub fn remove_all(&mut self, gear_id: GearId) {
-        let entry = self.lookup[gear_id.get() as usize - 1];
+    pub fn spawn_bundle(&mut self, gear_id: Id, bundle: &GearBundle) -> Result<(), RawAddError> {
+        for (component, bytes) in &bundle.components {
+            self.add_raw(gear_id.get(), *component, bytes)?;
+        }
+
+        let entry = self.lookup_entry(gear_id);
         if let Some(index) = entry.index {
-            self.remove_from_block(entry.block_index, index.get() - 1);
+            let mask = self.block_masks[entry.block_index as usize];
+            let new_mask = mask.with_tag(bundle.tag_mask);
+            if new_mask != mask {
+                let dest_block_index = self.ensure_block(new_mask);
+                self.move_between_blocks(entry.block_index, index.get() - 1, dest_block_index);
+            }
         }
+        Ok(())
     }
 
-    pub fn register<T: 'static>(&mut self) {
-        debug_assert!(!std::mem::needs_drop::<T>());
-        debug_assert!(size_of::<T>() <= u16::max_value() as usize);
+    /// Adds the `Flags` component to the gear if it is missing.
+    pub fn set_flag(&mut self, gear_id: Id, flag: u8) {
+        let flag_bit = flag_bit(flag);
+        self.register::<Flags>();
+        match self.get_mut::<Flags>(gear_id) {
+            Some(flags) => flags.0 |= flag_bit,
+            None => self.add(gear_id, &Flags(flag_bit)),
+        }
+    }
 
-        let id = TypeId::of::<T>();
+    pub fn clear_flag(&mut self, gear_id: Id, flag: u8) {
+        let flag_bit = flag_bit(flag);
+        if let Some(flags) = self.get_mut::<Flags>(gear_id) {
+            flags.0 &= !flag_bit;
+        }
+    }
+
+    pub fn has_flag(&self, gear_id: Id, flag: u8) -> bool {
+        let flag_bit = flag_bit(flag);
+        self.get_type_index::<Flags>()
+            .and_then(|type_index| self.get_component_ptr(gear_id, type_index))
+            .is_some_and(|ptr| unsafe { (*(ptr as *const Flags)).0 & flag_bit != 0 })
+    }
+
+    pub fn add_registering<T: Clone + 'static>(&mut self, gear_id: Id, value: &T) {
+        self.register::<T>();
         if size_of::<T>() == 0 {
-            if !self.tags.contains(&id) {
-                debug_assert!(self.tags.len() <= 64);
-                self.tags.push(id)
-            }
+            self.add_tag::<T>(gear_id);
         } else {
-            if !self.types.contains(&id) {
-                debug_assert!(self.types.len() <= 64);
-                self.element_sizes[self.types.len()] = size_of::<T>() as u16;
-                self.element_alignments[self.types.len()] = align_of::<T>() as u8;
-                self.types.push(id);
-            }
+            self.add(gear_id, value);
         }
     }
 
-    fn run_impl<T: TypeIter + 'static, F: FnMut(GearId, T)>(
+    pub fn populate_range<T: Clone + 'static, G: Fn(usize) -> T>(
         &mut self,
-        type_selector: u64,
-        included_tags: u64,
-        type_indices: &[i8],
-        mut f: F,
+        ids: Range<usize>,
+        make: G,
     ) {
+        self.register::<T>();
+        let block_index = match self.ensure_archetype::<(&T,)>() {
+            Some(index) => index,
+            None => return,
+        };
+        let capacity = self.blocks[block_index as usize].max_elements;
+        let mut ids = ids.peekable();
+
+        while ids.peek().is_some() {
+            let count = min(ids.len(), capacity as usize) as u16;
+            self.reserve_contiguous::<(&T,)>(count);
+            for id in ids.by_ref().take(count as usize) {
+                match Id::from_usize(id) {
+                    Some(gear_id) => self.add(gear_id, &make(id)),
+                    None => self.report_misuse("Invalid gear id"),
+                }
+            }
+        }
+    }
+
+    pub fn add_tag<T: 'static>(&mut self, gear_id: Id) {
+        if let Some(tag_index) = self.get_tag_index::<T>() {
+            let tag_bit = 1 << tag_index as u64;
+            let entry = self.lookup_entry(gear_id);
+
+            if let Some(index) = entry.index {
+                let mask = self.block_masks[entry.block_index as usize];
+                let new_mask = mask.with_tag(tag_bit);
+
+                if new_mask != mask {
+                    let dest_block_index = self.ensure_block(new_mask);
+                    self.move_between_blocks(entry.block_index, index.get() - 1, dest_block_index);
+                }
+            } else {
+                self.report_misuse("Cannot tag a gear with no data")
+            }
+        } else {
+            self.report_misuse(&format!("Unregistered tag {}", type_name::<T>()))
+        }
+    }
+
+    /// Adds `value` to every gear matching `Filter`, one source block at a time.
+    /// `T` can be either a data component or a tag.
+    pub fn add_to_all<Filter: TypeTuple, T: Clone + 'static>(&mut self, value: &T) {
+        let type_index = self.get_type_index::<T>();
+        let added = match (type_index, self.get_tag_index::<T>()) {
+            (Some(type_index), _) => BlockMask::new(1 << type_index as u64, 0),
+            (None, Some(tag_index)) => BlockMask::new(0, 1 << tag_index as u64),
+            (None, None) => {
+                return self.report_misuse(&format!("Unregistered type {}", type_name::<T>()))
+            }
+        };
+        let filter = match self.get_block_mask::<Filter>() {
+            Some(mask) => mask,
+            None => return,
+        };
+
+        for src_block_index in 0..self.blocks.len() {
+            let mask = self.block_masks[src_block_index];
+            let new_mask = mask.with_type(added.type_mask).with_tag(added.tag_mask);
+            if new_mask == mask
+                || !mask.type_mask.contains(&filter.type_mask)
+                || !mask.tag_mask.contains(&filter.tag_mask)
+            {
+                continue;
+            }
+
+            while self.blocks[src_block_index].elements_count > 0 {
+                let src_index = self.blocks[src_block_index].elements_count - 1;
+                let dest_block_index = self.ensure_block(new_mask);
+                match type_index {
+                    Some(type_index) => {
+                        let value = value.clone();
+                        let dest_index = self.move_between_blocks(
+                            src_block_index as u16,
+                            src_index,
+                            dest_block_index,
+                        );
+                        self.write_component(dest_block_index, dest_index, type_index, value);
+                    }
+                    None => {
+                        self.move_between_blocks(
+                            src_block_index as u16,
+                            src_index,
+                            dest_block_index,
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// Strips `T` from every gear that has it, one source block at a time.
+    /// `T` can be either a data component or a tag.
+    pub fn remove_from_all<T: 'static>(&mut self) {
+        let removed = match (self.get_type_index::<T>(), self.get_tag_index::<T>()) {
+            (Some(type_index), _) => BlockMask::new(1 << type_index as u64, 0),
+            (None, Some(tag_index)) => BlockMask::new(0, 1 << tag_index as u64),
+            (None, None) => {
+                return self.report_misuse(&format!("Unregistered type {}", type_name::<T>()))
+            }
+        };
+
+        for src_block_index in 0..self.blocks.len() {
+            let mask = self.block_masks[src_block_index];
+            let new_mask = BlockMask::new(
+                mask.type_mask & !removed.type_mask,
+                mask.tag_mask & !removed.tag_mask,
+            );
+            if new_mask == mask {
+                continue;
+            }
+
+            while self.blocks[src_block_index].elements_count > 0 {
+                let src_index = self.blocks[src_block_index].elements_count - 1;
+                if new_mask.type_mask == 0 {
+                    self.remove_from_block(src_block_index as u16, src_index);
+                } else {
+                    let dest_block_index = self.ensure_block(new_mask);
+                    self.move_between_blocks(src_block_index as u16, src_index, dest_block_index);
+                }
+            }
+        }
+    }
+
+    /// Live gears lacking the component or tag `T`, in ascending id order.
+    pub fn gears_without<T: 'static>(&self) -> Vec<Id> {
+        let missing = match (self.get_type_index::<T>(), self.get_tag_index::<T>()) {
+            (Some(type_index), _) => BlockMask::new(1 << type_index as u64, 0),
+            (None, Some(tag_index)) => BlockMask::new(0, 1 << tag_index as u64),
+            (None, None) => {
+                self.report_misuse(&format!("Unregistered type {}", type_name::<T>()));
+                return vec![];
+            }
+        };
+
+        let mut result: Vec<Id> = self
+            .blocks
+            .iter()
+            .zip(&self.block_masks)
+            .filter(|(_, mask)| {
+                !mask.type_mask.intersects(&missing.type_mask)
+                    && !mask.tag_mask.intersects(&missing.tag_mask)
+            })
+            .flat_map(|(block, _)| block.gear_ids()[0..block.elements_count as usize].iter())
+            .cloned()
+            .collect();
+        result.sort_unstable();
+        result
+    }
+
+    /// Presents the `T` array of every block as `LANES`-wide chunks followed by
+    /// the remaining elements, ready to be loaded into vector registers.
+    /// Chunks are only aligned to `T`, so use unaligned loads.
+    pub fn for_each_simd<T, const LANES: usize, F>(&mut self, mut f: F)
+    where
+        T: Copy + 'static,
+        F: FnMut(&[Id], &mut [[T; LANES]], &mut [T]),
+    {
+        assert!(LANES > 0, "Lane count must be positive");
+        let type_index = match self.get_type_index::<T>() {
+            Some(index) => index,
+            None => return self.report_misuse(&format!("Unregistered type {}", type_name::<T>())),
+        };
+
+        for (block, mask) in self.blocks.iter().zip(&self.block_masks) {
+            if mask.type_mask.has_bit(type_index) && block.elements_count > 0 {
+                let count = block.elements_count as usize;
+                let chunks_count = count / LANES;
+                let ptr = block.component_blocks[type_index].unwrap().as_ptr() as *mut T;
+                let (chunks, remainder) = unsafe {
+                    (
+                        slice::from_raw_parts_mut(ptr as *mut [T; LANES], chunks_count),
+                        slice::from_raw_parts_mut(
+                            ptr.add(chunks_count * LANES),
+                            count - chunks_count * LANES,
+                        ),
+                    )
+                };
+                f(&block.gear_ids()[0..count], chunks, remainder);
+            }
+        }
+    }
+
+    /// The live `T` array of every non-empty block carrying `T`.
+    pub fn component_slice<T: 'static>(&self) -> Vec<&[T]> {
+        let type_index = match self.get_type_index::<T>() {
+            Some(index) => index,
+            None => {
+                self.report_misuse(&format!("Unregistered type {}", type_name::<T>()));
+                return vec![];
+            }
+        };
+
+        self.blocks
+            .iter()
+            .zip(&self.block_masks)
+            .filter(|(block, mask)| mask.type_mask.has_bit(type_index) && block.elements_count > 0)
+            .map(|(block, _)| unsafe {
+                slice::from_raw_parts(
+                    block.component_blocks[type_index].unwrap().as_ptr() as *const T,
+                    block.elements_count as usize,
+                )
+            })
+            .collect()
+    }
+
+    pub fn component_stats<T: Copy + Into<f64> + 'static>(&self) -> Option<Stats> {
+        let type_index = self.get_type_index::<T>()?;
+        let mut count = 0;
+        let mut min = f64::INFINITY;
+        let mut max = f64::NEG_INFINITY;
+        let mut sum = 0.0;
+
+        for (block, mask) in self.blocks.iter().zip(&self.block_masks) {
+            if mask.type_mask.has_bit(type_index) {
+                let ptr = block.component_blocks[type_index].unwrap().as_ptr() as *const T;
+                let values = unsafe { slice::from_raw_parts(ptr, block.elements_count as usize) };
+                for value in values.iter().map(|value| (*value).into()) {
+                    min = min.min(value);
+                    max = max.max(value);
+                    sum += value;
+                    count += 1;
+                }
+            }
+        }
+
+        if count == 0 {
+            None
+        } else {
+            Some(Stats {
+                count,
+                min,
+                max,
+                mean: sum / count as f64,
+            })
+        }
+    }
+
+    pub fn gather<T: Copy + 'static>(&self, ids: &[Id], out: &mut [T]) -> usize {
+        debug_assert!(ids.len() <= out.len());
+        let type_index = match self.get_type_index::<T>() {
+            Some(index) => index,
+            None => {
+                self.report_misuse(&format!("Unregistered type {}", type_name::<T>()));
+                return 0;
+            }
+        };
+        let mut count = 0;
+
+        for (gear_id, value) in ids.iter().zip(out.iter_mut()) {
+            if let Some(ptr) = self.get_component_ptr(*gear_id, type_index) {
+                *value = unsafe { *(ptr as *const T) };
+                count += 1;
+            }
+        }
+        count
+    }
+
+    pub fn scatter<T: Copy + 'static>(&mut self, ids: &[Id], values: &[T]) -> Result<(), Id> {
+        if values.len() < ids.len() {
+            self.report_misuse(&format!(
+                "Cannot scatter {} values to {} gears",
+                values.len(),
+                ids.len()
+            ));
+            return Ok(());
+        }
+        let type_index = match self.get_type_index::<T>() {
+            Some(index) => index,
+            None => {
+                self.report_misuse(&format!("Unregistered type {}", type_name::<T>()));
+                return Ok(());
+            }
+        };
+
+        for (gear_id, value) in ids.iter().zip(values.iter()) {
+            if let Some(ptr) = self.get_component_ptr(*gear_id, type_index) {
+                unsafe { *(ptr as *mut T) = *value };
+            } else {
+                return Err(*gear_id);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn add_to_bucket(&mut self, gear_id: Id, bucket: u8) {
+        self.register::<SpatialBucket>();
+        let type_index = match self.get_type_index::<SpatialBucket>() {
+            Some(index) => index,
+            None => return,
+        };
+
+        if let Some(ptr) = self.get_component_ptr(gear_id, type_index) {
+            unsafe { *(ptr as *mut SpatialBucket) = SpatialBucket(bucket) };
+        } else {
+            self.add(gear_id, &SpatialBucket(bucket));
+        }
+    }
+
+    pub fn iter_bucket<T: TypeIter + 'static, F: FnMut(Id, T)>(&mut self, bucket: u8, mut f: F) {
+        let bucket_index = match self.get_type_index::<SpatialBucket>() {
+            Some(index) => index,
+            None => return,
+        };
+        let (selector, type_indices) = match self.get_selector::<T>() {
+            Some(selector) => selector,
+            None => return,
+        };
+        let bucket_selector = selector | 1 << bucket_index as u64;
         let mut slices = vec![null_mut(); type_indices.len() + 1];
 
-        for (block_index, mask) in self.block_masks.iter().enumerate() {
-            if mask.type_mask & type_selector == type_selector
-                && mask.tag_mask & included_tags == included_tags
-            {
-                let block = &mut self.blocks[block_index];
-                slices[0] = block.data.as_mut_ptr();
+        for (block_index, mask) in self.block_masks.iter().enumerate() {
+            if mask.type_mask.contains(&bucket_selector) {
+                let block = &self.blocks[block_index];
+                let buckets =
+                    block.component_blocks[bucket_index].unwrap().as_ptr() as *const SpatialBucket;
+                self.fill_slices(block_index, &type_indices, &mut slices);
+
+                for index in 0..block.elements_count as usize {
+                    if unsafe { (*buckets.add(index)).0 } == bucket {
+                        let (gear_id, x) = unsafe { T::get::<Id>(&slices[..], index) };
+                        f(gear_id, x);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn remove<T: 'static>(&mut self, gear_id: Id) {
+        if let Some(type_index) = self.get_type_index::<T>() {
+            self.remove_by_id(gear_id, type_index as ComponentId);
+        } else {
+            self.report_misuse(&format!("Unregistered type {}", type_name::<T>()))
+        }
+    }
+
+    pub fn remove_by_id(&mut self, gear_id: Id, component: ComponentId) {
+        if !self.is_registered(component) {
+            return self.report_misuse(&format!("Unregistered type with id {}", component));
+        }
+
+        let entry = self.lookup_entry(gear_id);
+        if let Some(index) = entry.index {
+            let mut dest_mask = self.block_masks[entry.block_index as usize];
+            dest_mask.type_mask &= !(1 << component as u64);
+
+            if dest_mask.type_mask == 0 {
+                self.remove_from_block(entry.block_index, index.get() - 1);
+            } else if dest_mask != self.block_masks[entry.block_index as usize] {
+                let dest_block_index = self.ensure_block(dest_mask);
+                self.move_between_blocks(entry.block_index, index.get() - 1, dest_block_index);
+            }
+        }
+    }
+
+    pub fn detach<T: Clone + 'static>(&mut self, gear_id: Id) -> Option<DetachedComponent<T>> {
+        let type_index = self.get_type_index::<T>()?;
+        let ptr = self.get_component_ptr(gear_id, type_index)?;
+        let value = unsafe { (*(ptr as *const T)).clone() };
+        self.remove_by_id(gear_id, type_index as ComponentId);
+        Some(DetachedComponent { value })
+    }
+
+    pub fn attach<T: Clone + 'static>(&mut self, gear_id: Id, component: DetachedComponent<T>) {
+        self.add(gear_id, &component.value);
+    }
+
+    pub fn reserve_id(&mut self, gear_id: Id) -> Result<(), IdInUse> {
+        self.ensure_lookup(gear_id);
+        let entry = &mut self.lookup[gear_id.to_usize() - 1];
+        if entry.is_free() {
+            *entry = LookupEntry::reserved();
+            Ok(())
+        } else {
+            Err(IdInUse)
+        }
+    }
+
+    pub fn remove_all(&mut self, gear_id: Id) {
+        let entry = self.lookup_entry(gear_id);
+        if let Some(index) = entry.index {
+            self.remove_from_block(entry.block_index, index.get() - 1);
+        }
+    }
+
+    /// Unlike `remove_all`, keeps the order of the remaining gears in the block
+    /// at the cost of shifting every element after the removed one.
+    pub fn remove_ordered(&mut self, gear_id: Id) {
+        let entry = self.lookup_entry(gear_id);
+        if let Some(index) = entry.index {
+            self.remove_ordered_from_block(entry.block_index, index.get() - 1);
+        }
+    }
+
+    /// Moves the gear to the front of its block so it is visited first during iteration.
+    pub fn prioritize(&mut self, gear_id: Id) {
+        let entry = self.lookup_entry(gear_id);
+        let index = match entry.index {
+            Some(index) if index.get() > 1 => (index.get() - 1) as usize,
+            _ => return,
+        };
+        let block = &mut self.blocks[entry.block_index as usize];
+
+        for (i, size) in self.element_sizes[0..self.types.len()]
+            .iter()
+            .map(|size| *size as usize)
+            .enumerate()
+        {
+            if let Some(ptr) = block.component_blocks[i] {
+                unsafe {
+                    swap_nonoverlapping(ptr.as_ptr(), ptr.as_ptr().add(size * index), size);
+                }
+            }
+        }
+
+        block.disabled_components.swap(0, index);
+        block.generations.swap(0, index);
+        block.versions.swap(0, index);
+        let gear_ids = block.gear_ids_mut();
+        gear_ids.swap(0, index);
+        self.lookup[gear_ids[index].to_usize() - 1] =
+            LookupEntry::new(entry.block_index, index as u16);
+        self.lookup[gear_id.to_usize() - 1] = LookupEntry::new(entry.block_index, 0);
+    }
+
+    /// Applies the operations queued by `f` only if all of them are valid.
+    pub fn transaction<F: FnOnce(&mut Transaction<Id>)>(
+        &mut self,
+        f: F,
+    ) -> Result<(), TransactionAborted> {
+        let mut transaction = Transaction::new(self);
+        f(&mut transaction);
+        if let Some(reason) = transaction.error {
+            return Err(TransactionAborted { reason });
+        }
+
+        for operation in transaction.operations {
+            operation(self);
+        }
+        Ok(())
+    }
+
+    fn next_compaction_move(&self) -> Option<(u16, u16)> {
+        for (dest_index, mask) in self.block_masks.iter().enumerate() {
+            if !self.blocks[dest_index].is_full() && !self.pinned.contains(mask) {
+                let src_index = (dest_index + 1..self.blocks.len())
+                    .rev()
+                    .find(|i| self.block_masks[*i] == *mask && self.blocks[*i].elements_count > 0);
+                if let Some(src_index) = src_index {
+                    return Some((src_index as u16, dest_index as u16));
+                }
+            }
+        }
+        None
+    }
+
+    /// Moves at most `budget` gears towards the front blocks of their archetypes.
+    /// Returns `true` if more work remains.
+    pub fn compact_step(&mut self, budget: usize) -> bool {
+        for _ in 0..budget {
+            match self.next_compaction_move() {
+                Some((src_block_index, dest_block_index)) => {
+                    let src_index = self.blocks[src_block_index as usize].elements_count - 1;
+                    self.move_between_blocks(src_block_index, src_index, dest_block_index);
+                }
+                None => return false,
+            }
+        }
+        self.next_compaction_move().is_some()
+    }
+
+    /// Packs the gears of every archetype into as few blocks as possible.
+    /// Emptied blocks are kept for reuse.
+    pub fn consolidate(&mut self) {
+        while self.compact_step(usize::MAX) {}
+    }
+
+    /// Removes every gear but keeps the blocks for reuse.
+    /// Removals are not recorded in the transition log.
+    /// Ids reserved with `reserve_id` stay reserved.
+    pub fn clear(&mut self) {
+        for block_index in 0..self.blocks.len() {
+            for index in 0..self.blocks[block_index].elements_count {
+                self.drop_components(block_index as u16, index);
+                let gear_id = self.blocks[block_index].gear_ids()[index as usize];
+                self.log_removal(gear_id);
+            }
+            let block = &mut self.blocks[block_index];
+            block.elements_count = 0;
+            block.disabled_components.clear();
+            block.generations.clear();
+            block.versions.clear();
+        }
+        for entry in self.lookup.iter_mut().filter(|entry| entry.index.is_some()) {
+            *entry = LookupEntry::default();
+        }
+    }
+
+    /// Removes every gear and frees all blocks, keeping the registered types.
+    pub fn clear_and_free(&mut self) {
+        self.clear();
+        self.retain_blocks(|_, _| false);
+        self.observed_transitions.clear();
+    }
+
+    /// Bytes taken by live gears divided by the bytes allocated for blocks.
+    pub fn fragmentation_ratio(&self) -> f32 {
+        if self.blocks.is_empty() {
+            return 1.0;
+        }
+        let used: usize = self
+            .blocks
+            .iter()
+            .map(|block| {
+                let row_size: usize = block
+                    .element_sizes
+                    .iter()
+                    .zip(block.component_blocks.iter())
+                    .filter(|(_, ptr)| ptr.is_some())
+                    .map(|(size, _)| *size as usize)
+                    .sum();
+                block.elements_count as usize * (row_size + size_of::<Id>())
+            })
+            .sum();
+        used as f32 / (self.blocks.len() * BLOCK_SIZE) as f32
+    }
+
+    /// Whether `shrink_to_fit` is likely to free a significant amount of memory.
+    pub fn should_compact(&self) -> bool {
+        self.blocks.len() > self.archetype_count()
+            && self.fragmentation_ratio() < COMPACTION_THRESHOLD
+    }
+
+    /// Consolidates and frees every empty block.
+    pub fn shrink_to_fit(&mut self) {
+        self.consolidate();
+        let pinned = self.pinned.clone();
+        self.retain_blocks(|block, mask| block.elements_count > 0 || pinned.contains(mask));
+    }
+
+    fn retain_blocks<F: FnMut(&DataBlock<Id>, &BlockMask) -> bool>(&mut self, mut f: F) {
+        let keep: Vec<bool> = self
+            .blocks
+            .iter()
+            .zip(&self.block_masks)
+            .map(|(block, mask)| f(block, mask))
+            .collect();
+        if keep.iter().all(|keep| *keep) {
+            return;
+        }
+
+        let mut block_keep = keep.iter();
+        let allocator = &mut self.block_allocator;
+        self.blocks.retain(|block| {
+            let keep = *block_keep.next().unwrap();
+            if !keep && !block.attached {
+                unsafe { allocator.release(block.data, block_layout()) };
+            }
+            keep
+        });
+        let mut mask_keep = keep.iter();
+        self.block_masks.retain(|_| *mask_keep.next().unwrap());
+
+        for (block_index, block) in self.blocks.iter().enumerate() {
+            for (index, gear_id) in block.gear_ids()[0..block.elements_count as usize]
+                .iter()
+                .enumerate()
+            {
+                self.lookup[gear_id.to_usize() - 1] =
+                    LookupEntry::new(block_index as u16, index as u16);
+            }
+        }
+        self.invalidate_queries();
+        self.open_blocks.clear();
+    }
+
+    /// FNV-1a over gear ids, tags and component bytes, ordered by gear id and by the `TypeId`
+    /// of each tag and component, so the registration order does not change the hash.
+    /// Component types with padding bytes are not supported, `Boxed` components are skipped.
+    pub fn state_hash(&self) -> u64 {
+        let mut hasher = StateHasher(0xcbf2_9ce4_8422_2325);
+        let type_slots = sorted_slots(&self.types);
+        let tag_slots = sorted_slots(&self.tags);
+
+        for (gear_index, entry) in self.lookup.iter().enumerate() {
+            if let Some(index) = entry.index {
+                let index = index.get() as usize - 1;
+                let mask = self.block_masks[entry.block_index as usize];
+                let block = &self.blocks[entry.block_index as usize];
+                hasher.write(&(gear_index as u64 + 1).to_le_bytes());
+
+                for tag_index in &tag_slots {
+                    if mask.tag_mask.has_bit(*tag_index) {
+                        self.tags[*tag_index].hash(&mut hasher);
+                    }
+                }
+                for type_index in &type_slots {
+                    let type_index = *type_index;
+                    if mask.type_mask.has_bit(type_index) && self.drop_fns[type_index].is_none() {
+                        let size = self.element_sizes[type_index] as usize;
+                        let ptr = block.component_blocks[type_index].unwrap().as_ptr();
+                        self.types[type_index].hash(&mut hasher);
+                        hasher.write(unsafe { slice::from_raw_parts(ptr.add(size * index), size) });
+                    }
+                }
+            }
+        }
+        hasher.finish()
+    }
+
+    pub fn debug_gear(&self, gear_id: Id) -> String {
+        let mut result = String::new();
+        let (block_index, index) = match self.locate(gear_id) {
+            Some(location) => location,
+            None => {
+                let _ = writeln!(result, "Gear {}: no data", gear_id);
+                return result;
+            }
+        };
+        let mask = self.block_masks[block_index as usize];
+        let _ = writeln!(
+            result,
+            "Gear {} (block {}, index {}) {{",
+            gear_id, block_index, index
+        );
+
+        for type_index in 0..self.types.len() {
+            if let Some(ptr) = self.get_component_ptr(gear_id, type_index) {
+                let size = self.element_sizes[type_index] as usize;
+                let _ = write!(
+                    result,
+                    "\tC{} {} ({} bytes): [",
+                    type_index, self.type_names[type_index], size
+                );
+                for byte in unsafe { slice::from_raw_parts(ptr, size) } {
+                    let _ = write!(result, "{:02x} ", byte);
+                }
+                let _ = writeln!(result, "]");
+            }
+        }
+        for tag_index in 0..self.tags.len() {
+            if mask.tag_mask.has_bit(tag_index) {
+                let _ = writeln!(result, "\tT{}", tag_index);
+            }
+        }
+        let _ = writeln!(result, "}}");
+        result
+    }
+
+    pub fn to_dot(&self) -> String {
+        let mut archetypes: Vec<(BlockMask, usize, usize)> = vec![];
+        for (block, mask) in self.blocks.iter().zip(&self.block_masks) {
+            match archetypes.iter_mut().find(|(m, _, _)| m == mask) {
+                Some((_, count, capacity)) => {
+                    *count += block.elements_count as usize;
+                    *capacity += block.max_elements as usize;
+                }
+                None => archetypes.push((
+                    *mask,
+                    block.elements_count as usize,
+                    block.max_elements as usize,
+                )),
+            }
+        }
+
+        let node_index = |mask: &BlockMask| archetypes.iter().position(|(m, _, _)| m == mask);
+        let mut result = String::from("digraph archetypes {\n");
+
+        for (index, (mask, count, capacity)) in archetypes.iter().enumerate() {
+            let mut label = String::new();
+            for i in (0..self.types.len()).filter(|i| mask.type_mask.has_bit(*i)) {
+                let _ = write!(label, "C{} ", i);
+            }
+            for i in (0..self.tags.len()).filter(|i| mask.tag_mask.has_bit(*i)) {
+                let _ = write!(label, "T{} ", i);
+            }
+            let _ = writeln!(
+                result,
+                "    a{} [label=\"{}\\n{}/{}\"];",
+                index,
+                label.trim_end(),
+                count,
+                capacity
+            );
+        }
+
+        for (from, to) in &self.observed_transitions {
+            if let (Some(from), Some(to)) = (node_index(from), node_index(to)) {
+                let _ = writeln!(result, "    a{} -> a{};", from, to);
+            }
+        }
+
+        result.push('}');
+        result
+    }
+
+    /// Disabled components stay in place but are skipped by `iter_enabled`.
+    pub fn set_enabled<T: 'static>(&mut self, gear_id: Id, enabled: bool) {
+        let type_index = match self.get_type_index::<T>() {
+            Some(index) => index,
+            None => return self.report_misuse(&format!("Unregistered type {}", type_name::<T>())),
+        };
+        let entry = self.lookup_entry(gear_id);
+        if let Some(index) = entry.index {
+            if self.block_masks[entry.block_index as usize]
+                .type_mask
+                .has_bit(type_index)
+            {
+                let block = &mut self.blocks[entry.block_index as usize];
+                let disabled = &mut block.disabled_components[index.get() as usize - 1];
+                if enabled {
+                    *disabled &= !(1 << type_index as u64);
+                } else {
+                    *disabled |= 1 << type_index as u64;
+                }
+            }
+        }
+    }
+
+    pub fn visit_all<V: ComponentVisitor<Id>>(&self, visitor: &mut V) {
+        for (block, mask) in self.blocks.iter().zip(&self.block_masks) {
+            for type_index in 0..self.types.len() {
+                if let (true, Some(as_any)) =
+                    (mask.type_mask.has_bit(type_index), self.any_fns[type_index])
+                {
+                    let size = self.element_sizes[type_index] as usize;
+                    let ptr = block.component_blocks[type_index].unwrap().as_ptr();
+                    for (index, gear_id) in block.gear_ids()[0..block.elements_count as usize]
+                        .iter()
+                        .enumerate()
+                    {
+                        let value = unsafe { as_any(&*ptr.add(size * index)) };
+                        visitor.visit(*gear_id, type_index as ComponentId, value);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Checks that blocks and the lookup table agree with each other.
+    pub fn validate(&self) -> Result<(), ValidationError<Id>> {
+        for (block_index, block) in self.blocks.iter().enumerate() {
+            let block_index = block_index as u16;
+            let count = block.elements_count as usize;
+            if block.elements_count > block.max_elements {
+                return Err(ValidationError::BlockOverflow(block_index));
+            }
+            if block.disabled_components.len() != count
+                || block.generations.len() != count
+                || block.versions.len() != count
+            {
+                return Err(ValidationError::MetadataLength(block_index));
+            }
+            if !block.pointers_valid() {
+                return Err(ValidationError::InvalidPointers(block_index));
+            }
+            for (index, gear_id) in block.gear_ids()[0..count].iter().enumerate() {
+                if self.locate(*gear_id) != Some((block_index, index as u16)) {
+                    return Err(ValidationError::StaleLookup(*gear_id));
+                }
+            }
+        }
+
+        for (gear_index, entry) in self.lookup.iter().enumerate() {
+            if let Some(index) = entry.index {
+                let gear_id = Id::from_usize(gear_index + 1).unwrap();
+                let stored_id = self
+                    .blocks
+                    .get(entry.block_index as usize)
+                    .filter(|block| index.get() <= block.elements_count)
+                    .map(|block| block.gear_ids()[index.get() as usize - 1]);
+                if stored_id != Some(gear_id) {
+                    return Err(ValidationError::DanglingLookup(gear_id));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn recompute_block_pointers(&mut self) {
+        for (block, mask) in self.blocks.iter_mut().zip(&self.block_masks) {
+            block.layout_components(
+                mask.type_mask,
+                &self.element_alignments[0..block.element_sizes.len()],
+            );
+        }
+    }
+
+    /// Component ids are assigned in registration order.
+    pub fn register<T: 'static>(&mut self) {
+        match self.try_register::<T>() {
+            Ok(_) => {}
+            Err(ComponentError::TooManyTypes) => self.report_misuse("Too many registered types"),
+            Err(error) => self.report_misuse(&format!(
+                "Cannot register {}: {:?}",
+                type_name::<T>(),
+                error
+            )),
+        }
+    }
+
+    fn resize_block_slots(&mut self) {
+        let types_count = self.types.len();
+        for block in &mut self.blocks {
+            let mut slots = block.component_blocks.to_vec();
+            slots.resize(types_count, None);
+            block.component_blocks = slots.into_boxed_slice();
+            block.element_sizes = Box::from(&self.element_sizes[0..types_count]);
+        }
+    }
+
+    pub fn register_checked<T: 'static>(&mut self) -> Result<ComponentId, ComponentError> {
+        check_component::<T>()?;
+        self.try_register::<T>()
+    }
+
+    /// For tags the returned id is the index among registered tags.
+    pub fn try_register<T: 'static>(&mut self) -> Result<ComponentId, ComponentError> {
+        debug_assert!(!std::mem::needs_drop::<T>());
+        if size_of::<T>() > u16::MAX as usize {
+            return Err(ComponentError::TooLarge);
+        } else if align_of::<T>() > u8::MAX as usize {
+            return Err(ComponentError::BadAlign);
+        }
+
+        let id = TypeId::of::<T>();
+        let registry = if size_of::<T>() == 0 {
+            &mut self.tags
+        } else {
+            &mut self.types
+        };
+
+        if let Some(index) = registry.iter().position(|t| *t == id) {
+            return Ok(index as ComponentId);
+        }
+        match claim_slot(registry, id) {
+            None => Err(ComponentError::TooManyTypes),
+            Some(index) => {
+                if size_of::<T>() != 0 {
+                    self.element_sizes[index] = size_of::<T>() as u16;
+                    self.element_alignments[index] = align_of::<T>() as u8;
+                    self.type_names[index] = type_name::<T>();
+                    self.any_fns[index] = Some(component_as_any::<T>);
+                    self.resize_block_slots();
+                }
+                self.invalidate_queries();
+                Ok(index as ComponentId)
+            }
+        }
+    }
+
+    /// Frees the component or tag slot of `T`, which must not be carried by any gear.
+    /// The ids of the other types do not change, the freed id goes to the next registered type.
+    /// The empty blocks of archetypes with `T` are freed, so that type starts without them.
+    pub fn unregister<T: 'static>(&mut self) -> Result<(), TypeInUse> {
+        let id = TypeId::of::<T>();
+        let is_tag = size_of::<T>() == 0;
+        let registry = if is_tag { &self.tags } else { &self.types };
+        let index = match registry.iter().position(|t| *t == id) {
+            Some(index) => index,
+            None => return Ok(()),
+        };
+        let mask_bit = |mask: &BlockMask| {
+            if is_tag {
+                mask.tag_mask.has_bit(index)
+            } else {
+                mask.type_mask.has_bit(index)
+            }
+        };
+        let in_use = self
+            .blocks
+            .iter()
+            .zip(&self.block_masks)
+            .any(|(block, mask)| block.elements_count > 0 && mask_bit(mask));
+        if in_use {
+            return Err(TypeInUse);
+        }
+        self.retain_blocks(|_, mask| !mask_bit(mask));
+
+        self.observed_transitions
+            .retain(|(from, to)| !mask_bit(from) && !mask_bit(to));
+        self.recycling.retain(|mask, _| !mask_bit(mask));
+        self.pinned.retain(|mask| !mask_bit(mask));
+        self.invalidate_queries();
+        self.open_blocks.clear();
+
+        if is_tag {
+            self.tags[index] = TypeId::of::<FreeSlot>();
+            return Ok(());
+        }
+
+        self.types[index] = TypeId::of::<FreeSlot>();
+        self.element_sizes[index] = 0;
+        self.element_alignments[index] = 0;
+        self.type_names[index] = "";
+        self.drop_fns[index] = None;
+        self.any_fns[index] = None;
+        self.resize_block_slots();
+        Ok(())
+    }
+
+    /// Registers `Boxed<T>`, which is queried as `&Boxed<T>` and dropped on removal.
+    /// Boxed components cannot be restored by `deserialize_compact`.
+    pub fn register_boxed<T: 'static>(&mut self) {
+        let id = TypeId::of::<Boxed<T>>();
+        if !self.types.contains(&id) {
+            let index = match claim_slot(&mut self.types, id) {
+                Some(index) => index,
+                None => return self.report_misuse("Too many registered types"),
+            };
+            self.element_sizes[index] = size_of::<Boxed<T>>() as u16;
+            self.element_alignments[index] = align_of::<Boxed<T>>() as u8;
+            self.type_names[index] = type_name::<Boxed<T>>();
+            self.drop_fns[index] = Some(drop_component::<Boxed<T>>);
+            self.any_fns[index] = Some(component_as_any::<Boxed<T>>);
+            self.resize_block_slots();
+        }
+    }
+
+    /// Registers `DoubleBuffered<T>`, which is added and queried as a regular component.
+    pub fn register_double_buffered<T: Clone + 'static>(&mut self) {
+        self.register::<DoubleBuffered<T>>();
+    }
+
+    /// Publishes the back buffer of every `DoubleBuffered<T>` as its front buffer.
+    /// The back buffer keeps the published value as the base for the next frame.
+    pub fn swap_buffers<T: Clone + 'static>(&mut self) {
+        let type_index = match self.get_type_index::<DoubleBuffered<T>>() {
+            Some(index) => index,
+            None => {
+                return self.report_misuse(&format!(
+                    "Unregistered type {}",
+                    type_name::<DoubleBuffered<T>>()
+                ))
+            }
+        };
+
+        for (block, mask) in self.blocks.iter().zip(&self.block_masks) {
+            if mask.type_mask.has_bit(type_index) {
+                let elements = unsafe {
+                    slice::from_raw_parts_mut(
+                        block.component_blocks[type_index].unwrap().as_ptr()
+                            as *mut DoubleBuffered<T>,
+                        block.elements_count as usize,
+                    )
+                };
+                for element in elements {
+                    element.front.clone_from(&element.back);
+                }
+            }
+        }
+    }
+
+    fn fill_slices(&self, block_index: usize, type_indices: &[i8], slices: &mut [*mut u8]) {
+        let block = &self.blocks[block_index];
+        debug_assert!(block.pointers_valid());
+        slices[0] = block.data.as_ptr();
+
+        for (arg_index, type_index) in type_indices.iter().cloned().enumerate() {
+            slices[arg_index + 1] = block.component_blocks[type_index as usize]
+                .unwrap()
+                .as_ptr()
+        }
+    }
+
+    fn invalidate_queries(&mut self) {
+        self.query_cache.clear();
+        self.layout_version += 1;
+    }
+
+    fn matching_blocks(&self, type_selector: u64, included_tags: u64) -> Vec<u16> {
+        self.block_masks
+            .iter()
+            .enumerate()
+            .filter(|(_, mask)| {
+                mask.type_mask.contains(&type_selector) && mask.tag_mask.contains(&included_tags)
+            })
+            .map(|(block_index, _)| block_index as u16)
+            .collect()
+    }
+
+    fn cache_query(&mut self, type_selector: u64, included_tags: u64) {
+        if !self
+            .query_cache
+            .contains_key(&(type_selector, included_tags))
+        {
+            let blocks = self.matching_blocks(type_selector, included_tags);
+            self.query_cache
+                .insert((type_selector, included_tags), blocks);
+        }
+    }
+
+    fn run_impl<T: TypeIter + 'static, F: FnMut(Id, T)>(
+        &mut self,
+        type_selector: u64,
+        included_tags: u64,
+        type_indices: &[i8],
+        f: F,
+    ) {
+        let mut slices = vec![];
+        self.run_with_slices(type_selector, included_tags, type_indices, &mut slices, f);
+    }
+
+    fn run_with_slices<T: TypeIter + 'static, F: FnMut(Id, T)>(
+        &mut self,
+        type_selector: u64,
+        included_tags: u64,
+        type_indices: &[i8],
+        slices: &mut Vec<*mut u8>,
+        mut f: F,
+    ) {
+        slices.clear();
+        slices.resize(type_indices.len() + 1, null_mut());
+        self.cache_query(type_selector, included_tags);
+        #[cfg(feature = "query-timings")]
+        self.query_timings.clear();
+
+        for block_index in &self.query_cache[&(type_selector, included_tags)] {
+            #[cfg(feature = "query-timings")]
+            let start = std::time::Instant::now();
+
+            let block_index = *block_index as usize;
+            self.fill_slices(block_index, type_indices, slices);
+            unsafe {
+                T::iter(
+                    &slices[..],
+                    self.blocks[block_index].elements_count as usize,
+                    &mut f,
+                );
+            }
+
+            #[cfg(feature = "query-timings")]
+            self.query_timings
+                .push((block_index as u16, start.elapsed().as_nanos() as u64));
+        }
+    }
+
+    #[cfg(feature = "query-timings")]
+    pub fn last_query_timings(&self) -> &[(u16, u64)] {
+        &self.query_timings
+    }
+
+    fn try_get_selector<T: TypeTuple>(&self) -> Result<(u64, Vec<i8>), &'static str> {
+        let mut arg_types = Vec::with_capacity(64);
+        T::get_types(&mut arg_types);
+        self.selector_from_types(&arg_types)
+    }
+
+    fn selector_from_types(&self, arg_types: &[TypeId]) -> Result<(u64, Vec<i8>), &'static str> {
+        let mut type_indices = vec![];
+        self.fill_selector(arg_types, &mut type_indices)
+            .map(|selector| (selector, type_indices))
+    }
+
+    fn fill_selector(
+        &self,
+        arg_types: &[TypeId],
+        type_indices: &mut Vec<i8>,
+    ) -> Result<u64, &'static str> {
+        type_indices.clear();
+        type_indices.resize(arg_types.len(), -1);
+        let mut selector = 0u64;
+
+        for (arg_index, type_id) in arg_types.iter().enumerate() {
+            match self.types.iter().position(|t| t == type_id) {
+                Some(i) if selector.has_bit(i) => return Err("Duplicate type"),
+                Some(i) => {
+                    type_indices[arg_index] = i as i8;
+                    selector |= 1 << i as u64;
+                }
+                None => return Err("Unregistered type"),
+            }
+        }
+        Ok(selector)
+    }
+
+    pub fn selector_for<T: TypeTuple>(&self) -> Option<u64> {
+        self.try_get_selector::<T>()
+            .ok()
+            .map(|(selector, _)| selector)
+    }
+
+    /// Splits the selector of `T` into the types it reads and the types it writes,
+    /// suitable for `queries_conflict`.
+    pub fn query_access<T: TypeTuple>(&self) -> Option<QueryAccess> {
+        let (_, type_indices) = self.try_get_selector::<T>().ok()?;
+        let mut mutability = Vec::with_capacity(type_indices.len());
+        T::get_mutability(&mut mutability);
+
+        let mut access = QueryAccess {
+            reads: 0,
+            writes: 0,
+        };
+        for (type_index, mutable) in type_indices.into_iter().zip(mutability) {
+            if mutable {
+                access.writes = access.writes.with_bit(type_index as usize);
+            } else {
+                access.reads = access.reads.with_bit(type_index as usize);
+            }
+        }
+        Some(access)
+    }
+
+    fn get_selector<T: TypeTuple>(&self) -> Option<(u64, Vec<i8>)> {
+        match self.try_get_selector::<T>() {
+            Ok(selector) => Some(selector),
+            Err(message) => {
+                self.report_misuse(&format!("{} in {}", message, type_name::<T>()));
+                None
+            }
+        }
+    }
+
+    pub fn iter<T: TypeIter + 'static>(&mut self) -> DataIterator<T, Id> {
+        //an empty index list makes the iterator skip the query
+        let (selector, type_indices) = self.get_selector::<T>().unwrap_or((0, vec![]));
+        DataIterator::new(self, selector, type_indices)
+    }
+
+    /// Runs `f` only if every queried type is registered, regardless of the error policy.
+    pub fn iter_opt<T: TypeIter + 'static, F: FnMut(Id, T)>(&mut self, f: F) {
+        if let Ok((selector, type_indices)) = self.try_get_selector::<T>() {
+            self.run_impl(selector, 0, &type_indices, f);
+        }
+    }
+
+    /// Same as `iter().run_id(f)`, but keeps its buffers in `scratch`
+    /// so that repeating the query does not allocate.
+    pub fn iter_scratch<T: TypeIter + 'static, F: FnMut(Id, T)>(
+        &mut self,
+        scratch: &mut QueryScratch,
+        f: F,
+    ) {
+        scratch.arg_types.clear();
+        T::get_types(&mut scratch.arg_types);
+        match self.fill_selector(&scratch.arg_types, &mut scratch.type_indices) {
+            Ok(selector) => {
+                self.run_with_slices(selector, 0, &scratch.type_indices, &mut scratch.slices, f)
+            }
+            Err(message) => self.report_misuse(&format!("{} in {}", message, type_name::<T>())),
+        }
+    }
+
+    pub fn archetype_slices<T: TypeIter + 'static>(&mut self) -> Vec<(&[Id], T::Slices<'_>)> {
+        let mut result = vec![];
+        let (selector, type_indices) = match self.get_selector::<T>() {
+            Some(selector) => selector,
+            None => return result,
+        };
+        let mut slices = vec![null_mut(); type_indices.len() + 1];
+
+        for (block_index, mask) in self.block_masks.iter().enumerate() {
+            if mask.type_mask.contains(&selector) {
+                let block = &self.blocks[block_index];
+                let count = block.elements_count as usize;
+                self.fill_slices(block_index, &type_indices, &mut slices);
+                result.push((&block.gear_ids()[0..count], unsafe {
+                    T::slices(&slices[..], count)
+                }));
+            }
+        }
+        result
+    }
+
+    /// Hands `f` the slices of the archetype made of exactly the types in `T`
+    /// if all of its gears are in a single block.
+    /// Gear ids stay read-only since the lookup table depends on them.
+    pub fn with_archetype_mut<T, R, F>(&mut self, f: F) -> Option<R>
+    where
+        T: TypeIter + 'static,
+        F: for<'a> FnOnce(&'a [Id], T::Slices<'a>) -> R,
+    {
+        let (selector, type_indices) = self.get_selector::<T>()?;
+        let mask = BlockMask::new(selector, 0);
+        let mut blocks = self
+            .block_masks
+            .iter()
+            .enumerate()
+            .filter(|(block_index, block_mask)| {
+                **block_mask == mask && self.blocks[*block_index].elements_count > 0
+            })
+            .map(|(block_index, _)| block_index);
+        let block_index = match (blocks.next(), blocks.next()) {
+            (Some(block_index), None) => block_index,
+            _ => return None,
+        };
+
+        let mut slices = vec![null_mut(); type_indices.len() + 1];
+        self.fill_slices(block_index, &type_indices, &mut slices);
+        let block = &self.blocks[block_index];
+        let count = block.elements_count as usize;
+        Some(f(&block.gear_ids()[0..count], unsafe {
+            T::slices(&slices[..], count)
+        }))
+    }
+
+    fn find_element<T: TypeIter + 'static, I: Iterator<Item = usize>, R, F: FnOnce(Id, T) -> R>(
+        &mut self,
+        block_indices: I,
+        last: bool,
+        f: F,
+    ) -> Option<R> {
+        let (selector, type_indices) = self.get_selector::<T>()?;
+        let mut slices = vec![null_mut(); type_indices.len() + 1];
+
+        for block_index in block_indices {
+            let count = self.blocks[block_index].elements_count as usize;
+            if count > 0 && self.block_masks[block_index].type_mask.contains(&selector) {
+                self.fill_slices(block_index, &type_indices, &mut slices);
+                let index = if last { count - 1 } else { 0 };
+                let (gear_id, x) = unsafe { T::get::<Id>(&slices[..], index) };
+                return Some(f(gear_id, x));
+            }
+        }
+        None
+    }
+
+    /// The element is only borrowed for the duration of `f`.
+    pub fn first_with<T: TypeIter + 'static, R, F: FnOnce(Id, T) -> R>(
+        &mut self,
+        f: F,
+    ) -> Option<R> {
+        self.find_element(0..self.blocks.len(), false, f)
+    }
+
+    pub fn last_with<T: TypeIter + 'static, R, F: FnOnce(Id, T) -> R>(
+        &mut self,
+        f: F,
+    ) -> Option<R> {
+        self.find_element((0..self.blocks.len()).rev(), true, f)
+    }
+
+    pub fn fold_id<T: TypeIter + 'static, A, F: FnMut(A, Id, T) -> A>(
+        &mut self,
+        init: A,
+        mut f: F,
+    ) -> A {
+        let mut accumulator = Some(init);
+        self.iter::<T>().run_id(|gear_id, x| {
+            accumulator = accumulator.take().map(|value| f(value, gear_id, x))
+        });
+        accumulator.unwrap()
+    }
+
+    pub fn iter_id_with<T: TypeIter + 'static, R, F: FnMut(&mut R, Id, T)>(
+        &mut self,
+        resource: &mut R,
+        mut f: F,
+    ) {
+        self.iter::<T>()
+            .run_id(|gear_id, x| f(resource, gear_id, x));
+    }
+
+    pub fn iter_join<T: TypeIter + 'static, X, F: FnMut(Id, T, &X)>(
+        &mut self,
+        external: &HashMap<Id, X>,
+        mut f: F,
+    ) {
+        self.iter::<T>().run_id(|gear_id, x| {
+            if let Some(value) = external.get(&gear_id) {
+                f(gear_id, x, value)
+            }
+        });
+    }
+
+    pub fn iter_copied<T: CopyTuple + 'static, F: FnMut(Id, T)>(&self, mut f: F) {
+        let mut arg_types = Vec::with_capacity(64);
+        T::get_types(&mut arg_types);
+        let (selector, type_indices) = match self.selector_from_types(&arg_types) {
+            Ok(selector) => selector,
+            Err(message) => {
+                return self.report_misuse(&format!("{} in {}", message, type_name::<T>()));
+            }
+        };
+        let mut slices = vec![null_mut(); type_indices.len() + 1];
+
+        for (block_index, mask) in self.block_masks.iter().enumerate() {
+            if mask.type_mask.contains(&selector) {
+                self.fill_slices(block_index, &type_indices, &mut slices);
+                for index in 0..self.blocks[block_index].elements_count as usize {
+                    let (gear_id, x) = unsafe { T::read::<Id>(&slices[..], index) };
+                    f(gear_id, x);
+                }
+            }
+        }
+    }
+
+    pub fn iter_ref<T: TupleOfRefs + 'static, F: FnMut(Id, T)>(&self, mut f: F) {
+        let (selector, type_indices) = match self.get_selector::<T>() {
+            Some(selector) => selector,
+            None => return,
+        };
+        let mut slices = vec![null_mut(); type_indices.len() + 1];
+
+        for (block_index, mask) in self.block_masks.iter().enumerate() {
+            if mask.type_mask.contains(&selector) {
+                self.fill_slices(block_index, &type_indices, &mut slices);
+                unsafe {
+                    T::iter(
+                        &slices[..],
+                        self.blocks[block_index].elements_count as usize,
+                        &mut f,
+                    );
+                }
+            }
+        }
+    }
+
+    /// Gears added after this call are visited by `iter_added_since` with the returned marker.
+    pub fn mark(&mut self) -> Generation {
+        self.generation += 1;
+        Generation(self.generation - 1)
+    }
+
+    pub fn iter_added_since<T: TypeIter + 'static, F: FnMut(Id, T)>(
+        &mut self,
+        generation: Generation,
+        mut f: F,
+    ) {
+        let (selector, type_indices) = match self.get_selector::<T>() {
+            Some(selector) => selector,
+            None => return,
+        };
+        let mut slices = vec![null_mut(); type_indices.len() + 1];
+
+        for (block_index, mask) in self.block_masks.iter().enumerate() {
+            if mask.type_mask.contains(&selector) {
+                self.fill_slices(block_index, &type_indices, &mut slices);
+                let block = &self.blocks[block_index];
+
+                for index in 0..block.elements_count as usize {
+                    if block.generations[index] > generation.0 {
+                        let (gear_id, x) = unsafe { T::get::<Id>(&slices[..], index) };
+                        f(gear_id, x);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn iter_enabled<T: TypeIter + 'static, F: FnMut(Id, T)>(&mut self, mut f: F) {
+        let (selector, type_indices) = match self.get_selector::<T>() {
+            Some(selector) => selector,
+            None => return,
+        };
+        let mut slices = vec![null_mut(); type_indices.len() + 1];
+
+        for (block_index, mask) in self.block_masks.iter().enumerate() {
+            if mask.type_mask.contains(&selector) {
+                self.fill_slices(block_index, &type_indices, &mut slices);
+                let block = &self.blocks[block_index];
+
+                for index in 0..block.elements_count as usize {
+                    if !block.disabled_components[index].intersects(&selector) {
+                        let (gear_id, x) = unsafe { T::get::<Id>(&slices[..], index) };
+                        f(gear_id, x);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn iter_with_flag<T: TypeIter + 'static, F: FnMut(Id, T)>(&mut self, flag: u8, mut f: F) {
+        let flag_bit = flag_bit(flag);
+        let (selector, type_indices) = match self.get_selector::<T>() {
+            Some(selector) => selector,
+            None => return,
+        };
+        let flags_index = match self.get_type_index::<Flags>() {
+            Some(index) => index,
+            None => return,
+        };
+        let mut slices = vec![null_mut(); type_indices.len() + 1];
+
+        for (block_index, mask) in self.block_masks.iter().enumerate() {
+            if mask.type_mask.contains(&selector) && mask.type_mask.has_bit(flags_index) {
+                self.fill_slices(block_index, &type_indices, &mut slices);
+                let block = &self.blocks[block_index];
+                let flags = unsafe {
+                    slice::from_raw_parts(
+                        block.component_blocks[flags_index].unwrap().as_ptr() as *const Flags,
+                        block.elements_count as usize,
+                    )
+                };
+
+                for (index, flags) in flags.iter().enumerate() {
+                    if flags.0 & flag_bit != 0 {
+                        let (gear_id, x) = unsafe { T::get::<Id>(&slices[..], index) };
+                        f(gear_id, x);
+                    }
+                }
+            }
+        }
+    }
+
+    pub fn zip_iter<T: TupleOfRefs + 'static, F: FnMut(Id, T, T)>(a: &Self, b: &Self, mut f: F) {
+        let (selector, type_indices) = match b.get_selector::<T>() {
+            Some(selector) => selector,
+            None => return,
+        };
+        let mut slices = vec![null_mut(); type_indices.len() + 1];
+
+        a.iter_ref(|gear_id, x: T| {
+            let entry = b.lookup_entry(gear_id);
+            if let Some(index) = entry.index {
+                let block_index = entry.block_index as usize;
+                if b.block_masks[block_index].type_mask.contains(&selector) {
+                    b.fill_slices(block_index, &type_indices, &mut slices);
+                    let (_, y) = unsafe { T::get::<Id>(&slices[..], index.get() as usize - 1) };
+                    f(gear_id, x, y);
+                }
+            }
+        });
+    }
+
+    /// For queries expected to match exactly one block, which is visited without the block loop.
+    /// A wrong hint panics in debug builds and falls back to a regular iteration otherwise.
+    pub fn iter_hint_single<T: TypeIter + 'static, F: FnMut(Id, T)>(&mut self, f: F) {
+        let (selector, type_indices) = match self.get_selector::<T>() {
+            Some(selector) => selector,
+            None => return,
+        };
+        self.cache_query(selector, 0);
+        let block_indices = &self.query_cache[&(selector, 0)];
+        debug_assert!(
+            block_indices.len() <= 1,
+            "Query matches more than one block"
+        );
+
+        if block_indices.len() != 1 {
+            self.run_impl(selector, 0, &type_indices, f);
+        } else {
+            let block_index = block_indices[0] as usize;
+            let mut slices = vec![null_mut(); type_indices.len() + 1];
+            self.fill_slices(block_index, &type_indices, &mut slices);
+            unsafe {
+                T::iter(
+                    &slices[..],
+                    self.blocks[block_index].elements_count as usize,
+                    f,
+                );
+            }
+        }
+    }
+
+    /// Visits the matching blocks whose index modulo `of` equals `partition`.
+    pub fn iter_partition<T: TypeIter + 'static, F: FnMut(Id, T)>(
+        &mut self,
+        partition: usize,
+        of: usize,
+        mut f: F,
+    ) {
+        debug_assert!(partition < of);
+        let (selector, type_indices) = match self.get_selector::<T>() {
+            Some(selector) => selector,
+            None => return,
+        };
+        self.cache_query(selector, 0);
+        let mut slices = vec![null_mut(); type_indices.len() + 1];
+
+        for block_index in &self.query_cache[&(selector, 0)] {
+            let block_index = *block_index as usize;
+            if block_index % of == partition {
+                self.fill_slices(block_index, &type_indices, &mut slices);
+                unsafe {
+                    T::iter(
+                        &slices[..],
+                        self.blocks[block_index].elements_count as usize,
+                        &mut f,
+                    );
+                }
+            }
+        }
+    }
+
+    pub fn iter_block<T: TypeIter + 'static, F: FnMut(Id, T)>(&mut self, block_index: u16, f: F) {
+        let (selector, type_indices) = match self.get_selector::<T>() {
+            Some(selector) => selector,
+            None => return,
+        };
+        let block_index = block_index as usize;
+
+        if block_index < self.blocks.len()
+            && self.block_masks[block_index].type_mask.contains(&selector)
+        {
+            let mut slices = vec![null_mut(); type_indices.len() + 1];
+            self.fill_slices(block_index, &type_indices, &mut slices);
+            unsafe {
+                T::iter(
+                    &slices[..],
+                    self.blocks[block_index].elements_count as usize,
+                    f,
+                );
+            }
+        }
+    }
+
+    /// Calls `begin` with the data types of each non-empty matching block, then `elem`
+    /// for every element of the block, then `end` with the state returned by `begin`.
+    pub fn iter_blocks<T, S, Begin, Elem, End>(
+        &mut self,
+        mut begin: Begin,
+        mut elem: Elem,
+        mut end: End,
+    ) where
+        T: TypeIter + 'static,
+        Begin: FnMut(&[TypeId]) -> S,
+        Elem: FnMut(&mut S, Id, T),
+        End: FnMut(S),
+    {
+        let (selector, type_indices) = match self.get_selector::<T>() {
+            Some(selector) => selector,
+            None => return,
+        };
+        self.cache_query(selector, 0);
+        let mut slices = vec![null_mut(); type_indices.len() + 1];
+        let mut block_types = Vec::with_capacity(self.types.len());
+
+        for block_index in &self.query_cache[&(selector, 0)] {
+            let block_index = *block_index as usize;
+            let count = self.blocks[block_index].elements_count as usize;
+            if count == 0 {
+                continue;
+            }
+            let mask = self.block_masks[block_index].type_mask;
+            block_types.clear();
+            block_types.extend(
+                self.types
+                    .iter()
+                    .enumerate()
+                    .filter(|(i, _)| mask.has_bit(*i))
+                    .map(|(_, type_id)| *type_id),
+            );
+
+            let mut state = begin(&block_types);
+            self.fill_slices(block_index, &type_indices, &mut slices);
+            unsafe {
+                T::iter(&slices[..], count, |gear_id, x| {
+                    elem(&mut state, gear_id, x)
+                });
+            }
+            end(state);
+        }
+    }
+
+    /// Visits every unordered pair of gears sharing an archetype. Limited to shared
+    /// references, since both elements of a pair may be borrowed at once.
+    pub fn iter_pairs<T: TupleOfRefs + Copy + 'static, F: FnMut((Id, T), (Id, T))>(
+        &self,
+        mut f: F,
+    ) {
+        let (selector, type_indices) = match self.get_selector::<T>() {
+            Some(selector) => selector,
+            None => return,
+        };
+        let mut archetypes: Vec<(BlockMask, Vec<usize>)> = vec![];
+        for (block_index, mask) in self.block_masks.iter().enumerate() {
+            if mask.type_mask.contains(&selector) {
+                match archetypes.iter_mut().find(|(m, _)| m == mask) {
+                    Some((_, blocks)) => blocks.push(block_index),
+                    None => archetypes.push((*mask, vec![block_index])),
+                }
+            }
+        }
+
+        for (_, block_indices) in archetypes {
+            let mut elements = vec![];
+            for block_index in block_indices {
+                let mut slices = vec![null_mut(); type_indices.len() + 1];
+                self.fill_slices(block_index, &type_indices, &mut slices);
+                let count = self.blocks[block_index].elements_count as usize;
+                for index in 0..count {
+                    elements.push(unsafe { T::get::<Id>(&slices[..], index) });
+                }
+            }
+
+            for (i, first) in elements.iter().enumerate() {
+                for second in &elements[i + 1..] {
+                    f(*first, *second);
+                }
+            }
+        }
+    }
+
+    /// Block and element indices are only valid until the next structural change.
+    pub fn iter_located<T: TypeIter + 'static, F: FnMut(Id, u16, u16, T)>(&mut self, mut f: F) {
+        let (selector, type_indices) = match self.get_selector::<T>() {
+            Some(selector) => selector,
+            None => return,
+        };
+        let mut slices = vec![null_mut(); type_indices.len() + 1];
+
+        for (block_index, mask) in self.block_masks.iter().enumerate() {
+            if mask.type_mask.contains(&selector) {
+                self.fill_slices(block_index, &type_indices, &mut slices);
+                let mut index = 0;
+                unsafe {
+                    T::iter(
+                        &slices[..],
+                        self.blocks[block_index].elements_count as usize,
+                        |gear_id, x| {
+                            f(gear_id, block_index as u16, index, x);
+                            index += 1;
+                        },
+                    );
+                }
+            }
+        }
+    }
+
+    pub fn iter_id_ordered<T: TypeIter + 'static, F: FnMut(Id, T)>(&mut self, f: F) {
+        self.iter_id_range(0, usize::MAX, f)
+    }
+
+    /// Visits the matching elements at positions `start..start + len` in gear id order,
+    /// so that successive calls can spread the work over several frames.
+    /// Every call sorts all matching elements.
+    pub fn iter_id_range<T: TypeIter + 'static, F: FnMut(Id, T)>(
+        &mut self,
+        start: usize,
+        len: usize,
+        mut f: F,
+    ) {
+        let (selector, type_indices) = match self.get_selector::<T>() {
+            Some(selector) => selector,
+            None => return,
+        };
+        let mut locations = vec![];
+
+        for (block_index, mask) in self.block_masks.iter().enumerate() {
+            if mask.type_mask.contains(&selector) {
+                let block = &self.blocks[block_index];
+                for (index, gear_id) in block.gear_ids()[0..block.elements_count as usize]
+                    .iter()
+                    .enumerate()
+                {
+                    locations.push((*gear_id, block_index, index));
+                }
+            }
+        }
+        locations.sort_unstable_by_key(|(gear_id, _, _)| *gear_id);
+
+        let mut slices = vec![null_mut(); type_indices.len() + 1];
+        for (_, block_index, index) in locations.into_iter().skip(start).take(len) {
+            self.fill_slices(block_index, &type_indices, &mut slices);
+            let (gear_id, x) = unsafe { T::get::<Id>(&slices[..], index) };
+            f(gear_id, x);
+        }
+    }
+
+    /// Calls `f` once per run of consecutive gear ids within a block.
+    /// Elements of each block are sorted by id first, which costs O(n log n) per block.
+    pub fn iter_id_ranges<T, F>(&mut self, mut f: F)
+    where
+        T: TypeIter + 'static,
+        F: FnMut(RangeInclusive<Id>, &mut [T]),
+    {
+        let (selector, type_indices) = match self.get_selector::<T>() {
+            Some(selector) => selector,
+            None => return,
+        };
+        let mut slices = vec![null_mut(); type_indices.len() + 1];
+        let mut order = vec![];
+        let mut run = vec![];
+
+        for (block_index, mask) in self.block_masks.iter().enumerate() {
+            if !mask.type_mask.contains(&selector) {
+                continue;
+            }
+            let block = &self.blocks[block_index];
+            let gear_ids = &block.gear_ids()[0..block.elements_count as usize];
+            order.clear();
+            order.extend(0..gear_ids.len());
+            order.sort_unstable_by_key(|index| gear_ids[*index]);
+            self.fill_slices(block_index, &type_indices, &mut slices);
+
+            let mut start = None;
+            for (position, index) in order.iter().enumerate() {
+                let (gear_id, x) = unsafe { T::get::<Id>(&slices[..], *index) };
+                start.get_or_insert(gear_id);
+                run.push(x);
+
+                let next = order
+                    .get(position + 1)
+                    .map(|next| gear_ids[*next].to_usize());
+                if next.is_none() || next != gear_id.to_usize().checked_add(1) {
+                    f(start.take().unwrap()..=gear_id, &mut run);
+                    run.clear();
+                }
+            }
+        }
+    }
+}
+
+/// Reusable buffers for `GearDataManager::iter_scratch`.
+#[derive(Default)]
+pub struct QueryScratch {
+    arg_types: Vec<TypeId>,
+    type_indices: Vec<i8>,
+    slices: Vec<*mut u8>,
+}
+
+pub struct DataIterator<'a, T, Id: GearIdType = GearId> {
+    data: &'a mut GearDataManager<Id>,
+    types: u64,
+    type_indices: Vec<i8>,
+    tags: u64,
+    phantom_types: PhantomData<T>,
+}
+
+impl<'a, T: TypeIter + 'static, Id: GearIdType> DataIterator<'a, T, Id> {
+    fn new(
+        data: &'a mut GearDataManager<Id>,
+        types: u64,
+        type_indices: Vec<i8>,
+    ) -> DataIterator<'a, T, Id> {
+        Self {
+            data,
+            types,
+            type_indices,
+            tags: 0,
+            phantom_types: PhantomData,
+        }
+    }
+
+    pub fn with_tags<U: TypeTuple + 'static>(self) -> Self {
+        let mut tag_types = Vec::with_capacity(64);
+        U::get_types(&mut tag_types);
+        let mut tags = 0;
+
+        for (i, tag) in self.data.tags.iter().enumerate() {
+            if tag_types.contains(tag) {
+                tags |= 1 << i as u64;
+            }
+        }
+        Self { tags, ..self }
+    }
+
+    #[inline]
+    pub fn run<F: FnMut(T)>(&mut self, mut f: F) {
+        self.run_id(|_, x| f(x))
+    }
+
+    #[inline]
+    pub fn run_id<F: FnMut(Id, T)>(&mut self, f: F) {
+        if self.type_indices.is_empty() {
+            return;
+        }
+        self.data
+            .run_impl(self.types, self.tags, &self.type_indices, f);
+    }
+}
+
+/// A query plan that outlives a single iteration.
+/// The plan is rebuilt when the manager adds or drops blocks.
+pub struct Query<T> {
+    selector: u64,
+    type_indices: Vec<i8>,
+    blocks: Vec<u16>,
+    slices: Vec<*mut u8>,
+    manager_id: u64,
+    layout_version: u64,
+    phantom_types: PhantomData<T>,
+}
+
+impl<T: TypeIter + 'static> Query<T> {
+    pub fn new<Id: GearIdType>(data: &GearDataManager<Id>) -> Self {
+        let mut query = Self {
+            selector: 0,
+            type_indices: vec![],
+            blocks: vec![],
+            slices: vec![],
+            manager_id: data.id,
+            layout_version: 0,
+            phantom_types: PhantomData,
+        };
+        query.refresh(data);
+        query
+    }
+
+    /// A query run on another manager than the one it was last resolved for is stale too.
+    pub fn is_stale<Id: GearIdType>(&self, data: &GearDataManager<Id>) -> bool {
+        self.manager_id != data.id || self.layout_version != data.layout_version
+    }
+
+    fn refresh<Id: GearIdType>(&mut self, data: &GearDataManager<Id>) {
+        //an empty index list makes the query match nothing
+        let (selector, type_indices) = data.get_selector::<T>().unwrap_or((0, vec![]));
+        self.blocks = if type_indices.is_empty() {
+            vec![]
+        } else {
+            data.matching_blocks(selector, 0)
+        };
+        self.selector = selector;
+        self.slices = vec![null_mut(); type_indices.len() + 1];
+        self.type_indices = type_indices;
+        self.manager_id = data.id;
+        self.layout_version = data.layout_version;
+    }
+
+    #[inline]
+    pub fn run<Id: GearIdType, F: FnMut(T)>(&mut self, data: &mut GearDataManager<Id>, mut f: F) {
+        self.run_id(data, |_, x| f(x))
+    }
+
+    pub fn run_id<Id: GearIdType, F: FnMut(Id, T)>(
+        &mut self,
+        data: &mut GearDataManager<Id>,
+        mut f: F,
+    ) {
+        if self.is_stale(data) {
+            self.refresh(data);
+        }
+        for block_index in &self.blocks {
+            let block_index = *block_index as usize;
+            data.fill_slices(block_index, &self.type_indices, &mut self.slices);
+            unsafe {
+                T::iter(
+                    &self.slices[..],
+                    data.blocks[block_index].elements_count as usize,
+                    &mut f,
+                );
+            }
+        }
+    }
+}
+
+pub trait System {
+    fn run(&mut self, data: &mut GearDataManager);
+}
+
+impl<F: FnMut(&mut GearDataManager)> System for F {
+    fn run(&mut self, data: &mut GearDataManager) {
+        self(data)
+    }
+}
+
+/// Runs its systems one after another in the order they were added.
+#[derive(Default)]
+pub struct Schedule {
+    systems: Vec<Box<dyn System>>,
+}
+
+impl Schedule {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_system<S: System + 'static>(&mut self, system: S) {
+        self.systems.push(Box::new(system));
+    }
+
+    pub fn run(&mut self, data: &mut GearDataManager) {
+        for system in &mut self.systems {
+            system.run(data);
+        }
+    }
+}
+
+type Operation<Id> = Box<dyn FnOnce(&mut GearDataManager<Id>)>;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct TransactionAborted {
+    pub reason: String,
+}
+
+/// Structural changes queued by `GearDataManager::transaction`.
+/// Each operation is validated against the masks left by the operations queued before it.
+pub struct Transaction<'a, Id: GearIdType = GearId> {
+    data: &'a GearDataManager<Id>,
+    operations: Vec<Operation<Id>>,
+    masks: HashMap<Id, BlockMask>,
+    error: Option<String>,
+}
+
+impl<'a, Id: GearIdType> Transaction<'a, Id> {
+    fn new(data: &'a GearDataManager<Id>) -> Self {
+        Self {
+            data,
+            operations: vec![],
+            masks: HashMap::new(),
+            error: None,
+        }
+    }
+
+    fn fail(&mut self, reason: String) {
+        self.error.get_or_insert(reason);
+    }
+
+    fn mask_of(&self, gear_id: Id) -> BlockMask {
+        match self.masks.get(&gear_id) {
+            Some(mask) => *mask,
+            None => match self.data.lookup.get(gear_id.to_usize() - 1) {
+                Some(LookupEntry {
+                    index: Some(_),
+                    block_index,
+                }) => self.data.block_masks[*block_index as usize],
+                _ => BlockMask::new(0, 0),
+            },
+        }
+    }
+
+    pub fn add<T: Clone + 'static>(&mut self, gear_id: Id, value: &T) {
+        let type_index = match self.data.get_type_index::<T>() {
+            Some(index) => index,
+            None => return self.fail(format!("Unregistered type {}", type_name::<T>())),
+        };
+        let mask = self.mask_of(gear_id).with_type(1 << type_index as u64);
+        if self.data.mask_capacity(mask.type_mask) == 0 {
+            return self.fail("Archetype components do not fit in a block".to_string());
+        }
+        self.masks.insert(gear_id, mask);
+        let value = value.clone();
+        self.operations
+            .push(Box::new(move |data| data.add(gear_id, &value)));
+    }
+
+    pub fn add_tag<T: 'static>(&mut self, gear_id: Id) {
+        let tag_index = match self.data.get_tag_index::<T>() {
+            Some(index) => index,
+            None => return self.fail(format!("Unregistered tag {}", type_name::<T>())),
+        };
+        let mask = self.mask_of(gear_id);
+        if mask.type_mask == 0 {
+            return self.fail("Cannot tag a gear with no data".to_string());
+        }
+        self.masks
+            .insert(gear_id, mask.with_tag(1 << tag_index as u64));
+        self.operations
+            .push(Box::new(move |data| data.add_tag::<T>(gear_id)));
+    }
+
+    pub fn remove<T: 'static>(&mut self, gear_id: Id) {
+        let type_index = match self.data.get_type_index::<T>() {
+            Some(index) => index,
+            None => return self.fail(format!("Unregistered type {}", type_name::<T>())),
+        };
+        let mut mask = self.mask_of(gear_id);
+        mask.type_mask &= !(1 << type_index as u64);
+        if mask.type_mask == 0 {
+            mask = BlockMask::new(0, 0);
+        }
+        self.masks.insert(gear_id, mask);
+        self.operations
+            .push(Box::new(move |data| data.remove::<T>(gear_id)));
+    }
+
+    pub fn remove_all(&mut self, gear_id: Id) {
+        self.masks.insert(gear_id, BlockMask::new(0, 0));
+        self.operations
+            .push(Box::new(move |data| data.remove_all(gear_id)));
+    }
+}
+
+#[macro_export]
+macro_rules! query {
+    ($manager: expr, ($($name: ident: $t: ty),+ $(,)?) => $body: block) => {
+        $manager.iter().run(|($($name),+,): ($($t),+,)| $body)
+    };
+    ($manager: expr, $gear_id: ident, ($($name: ident: $t: ty),+ $(,)?) => $body: block) => {
+        $manager
+            .iter()
+            .run_id(|$gear_id, ($($name),+,): ($($t),+,)| $body)
+    };
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        super::common::GearId, clamp_block_capacity, is_valid_component, queries_conflict,
+        ArchetypeTooWide, BlockAllocator, Boxed, ComponentError, ComponentId, ComponentVisitor,
+        DoubleBuffered, ErrorPolicy, GearDataManager, IdInUse, Mask, OutOfBlocks, Query,
+        QueryAccess, QueryScratch, RawAddError, Schedule, Stats, System, TypeInUse,
+    };
+    use std::{
+        alloc::{alloc, dealloc, Layout},
+        any::{Any, TypeId},
+        cell::RefCell,
+        collections::HashMap,
+        mem::size_of,
+        panic::{catch_unwind, AssertUnwindSafe},
+        ptr::NonNull,
+        rc::Rc,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    #[derive(Clone)]
+    struct Datum {
+        value: u32,
+    }
+
+    #[derive(Clone)]
+    struct Tag;
+
+    #[derive(Clone)]
+    struct Numbered<const N: usize>(u8);
+
+    macro_rules! register_numbered {
+        ($manager: expr, $($n: literal)+) => {
+            $(assert_eq!($manager.try_register::<Numbered<$n>>(), Ok($n));)+
+        };
+    }
+
+    #[derive(Clone)]
+    struct Position {
+        x: u32,
+        y: u32,
+    }
+
+    #[test]
+    fn single_component_iteration() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        for i in 1..=5 {
+            manager.add(GearId::new(i as u16).unwrap(), &Datum { value: i });
+        }
+
+        let mut sum = 0;
+        manager.iter().run(|(d,): (&Datum,)| sum += d.value);
+        assert_eq!(sum, 15);
+
+        manager.iter().run(|(d,): (&mut Datum,)| d.value += 1);
+        manager.iter().run(|(d,): (&Datum,)| sum += d.value);
+        assert_eq!(sum, 35);
+    }
+
+    #[test]
+    fn tagged_component_iteration() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Tag>();
+        for i in 1..=10 {
+            let gear_id = GearId::new(i as u16).unwrap();
+            manager.add(gear_id, &Datum { value: i });
+        }
+
+        for i in 1..=10 {
+            let gear_id = GearId::new(i as u16).unwrap();
+            if i & 1 == 0 {
+                manager.add_tag::<Tag>(gear_id);
+            }
+        }
+
+        let mut sum = 0;
+        manager.iter().run(|(d,): (&Datum,)| sum += d.value);
+        assert_eq!(sum, 55);
+
+        let mut tag_sum = 0;
+        manager
+            .iter()
+            .with_tags::<&Tag>()
+            .run(|(d,): (&Datum,)| tag_sum += d.value);
+        assert_eq!(tag_sum, 30);
+    }
+
+    #[test]
+    fn eager_archetype() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+
+        let block_index = manager.ensure_archetype::<(&Datum,)>().unwrap();
+        assert_eq!(manager.blocks.len(), 1);
+
+        for i in 1..=5 {
+            let gear_id = GearId::new(i as u16).unwrap();
+            manager.add(gear_id, &Datum { value: i });
+            assert_eq!(manager.lookup[i as usize - 1].block_index, block_index);
+        }
+        assert_eq!(manager.blocks.len(), 1);
+
+        let pair_block_index = manager.ensure_archetype::<(&Datum, &Position)>().unwrap();
+        let gear_id = GearId::new(1).unwrap();
+        manager.add(gear_id, &Position { x: 1, y: 2 });
+        assert_eq!(manager.lookup[0].block_index, pair_block_index);
+        assert_eq!(manager.blocks.len(), 2);
+
+        let mut count = 0;
+        manager.iter().run(|(p,): (&Position,)| {
+            assert_eq!((p.x, p.y), (1, 2));
+            count += 1;
+        });
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn remove_by_component_id() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+
+        let gear_id = GearId::new(1).unwrap();
+        manager.add(gear_id, &Datum { value: 1 });
+        manager.add(gear_id, &Position { x: 0, y: 0 });
+
+        let position_id = manager.component_id::<Position>().unwrap();
+        manager.remove_by_id(gear_id, position_id);
+        assert!(!manager.contains::<Position>(gear_id));
+        assert!(manager.contains::<Datum>(gear_id));
+
+        let datum_id = manager.component_id::<Datum>().unwrap();
+        manager.remove_by_id(gear_id, datum_id);
+        assert!(!manager.contains::<Datum>(gear_id));
+        assert!(manager.lookup[0].index.is_none());
+    }
+
+    #[test]
+    fn id_ordered_iteration() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+
+        for i in (1..=10).rev() {
+            let gear_id = GearId::new(i as u16).unwrap();
+            manager.add(gear_id, &Datum { value: i });
+            if i % 3 == 0 {
+                manager.add(gear_id, &Position { x: i, y: i });
+            }
+        }
+
+        let mut ids = vec![];
+        manager.iter_id_ordered(|gear_id, (d,): (&Datum,)| {
+            assert_eq!(d.value, gear_id.get() as u32);
+            ids.push(gear_id.get());
+        });
+        assert_eq!(ids, (1..=10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn gather_components() {
+        let mut manager = GearDataManager::new();
+        manager.register::<u32>();
+        for i in 1..=5 {
+            manager.add(GearId::new(i).unwrap(), &(i as u32 * 10));
+        }
+
+        let ids: Vec<_> = [4, 2, 7, 5]
+            .iter()
+            .map(|i| GearId::new(*i).unwrap())
+            .collect();
+        let mut out = [0u32; 4];
+        assert_eq!(manager.gather(&ids, &mut out), 3);
+        assert_eq!(out, [40, 20, 0, 50]);
+    }
+
+    #[test]
+    fn scatter_components() {
+        let mut manager = GearDataManager::new();
+        manager.register::<u32>();
+        for i in 1..=5 {
+            manager.add(GearId::new(i).unwrap(), &0u32);
+        }
+
+        let ids: Vec<_> = (1..=5).map(|i| GearId::new(i).unwrap()).collect();
+        let values: Vec<_> = (1..=5u32).map(|i| i * 100).collect();
+        assert_eq!(manager.scatter(&ids, &values), Ok(()));
+
+        manager.iter().run_id(|gear_id, (v,): (&u32,)| {
+            assert_eq!(*v, gear_id.get() as u32 * 100);
+        });
+
+        let missing = GearId::new(6).unwrap();
+        assert_eq!(manager.scatter(&[missing], &[1u32]), Err(missing));
+    }
+
+    #[test]
+    fn scatter_short_values() {
+        let mut manager = GearDataManager::new();
+        manager.register::<u32>();
+        manager.set_error_policy(ErrorPolicy::LogAndSkip(|_| {}));
+        for i in 1..=3 {
+            manager.add(GearId::new(i).unwrap(), &0u32);
+        }
+
+        let ids: Vec<_> = (1..=3).map(|i| GearId::new(i).unwrap()).collect();
+        assert_eq!(manager.scatter(&ids, &[1u32, 2]), Ok(()));
+        manager.iter().run(|(v,): (&u32,)| assert_eq!(*v, 0));
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot scatter 1 values to 2 gears")]
+    fn scatter_short_values_panics() {
+        let mut manager = GearDataManager::new();
+        manager.register::<u32>();
+        let ids = [GearId::new(1).unwrap(), GearId::new(2).unwrap()];
+        let _ = manager.scatter(&ids, &[1u32]);
+    }
+
+    #[test]
+    fn cached_query() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+        for i in 1..=4 {
+            let gear_id = GearId::new(i as u16).unwrap();
+            manager.add(gear_id, &Datum { value: i });
+            if i > 2 {
+                manager.add(gear_id, &Position { x: i, y: i });
+            }
+        }
+
+        let mut sum = 0;
+        manager.iter().run(|(d,): (&Datum,)| sum += d.value);
+        let selector = manager.get_selector::<(&Datum,)>().unwrap().0;
+        let cached = manager.query_cache[&(selector, 0)].as_ptr();
+        assert_eq!(manager.query_cache[&(selector, 0)], [0, 1]);
+
+        manager.iter().run(|(d,): (&Datum,)| sum += d.value);
+        assert_eq!(manager.query_cache[&(selector, 0)].as_ptr(), cached);
+        assert_eq!(sum, 20);
+
+        manager.add(GearId::new(1).unwrap(), &Position { x: 0, y: 0 });
+        manager.remove::<Datum>(GearId::new(1).unwrap());
+        assert!(manager.query_cache.is_empty());
+    }
+
+    #[test]
+    fn bucket_iteration() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        for i in 1..=6 {
+            let gear_id = GearId::new(i as u16).unwrap();
+            manager.add(gear_id, &Datum { value: i });
+            manager.add_to_bucket(gear_id, (i % 2) as u8);
+        }
+        manager.add_to_bucket(GearId::new(6).unwrap(), 1);
+
+        let mut even = vec![];
+        manager.iter_bucket(0, |gear_id, (_,): (&Datum,)| even.push(gear_id.get()));
+        even.sort();
+        assert_eq!(even, [2, 4]);
+
+        let mut odd_sum = 0;
+        manager.iter_bucket(1, |_, (d,): (&Datum,)| odd_sum += d.value);
+        assert_eq!(odd_sum, 1 + 3 + 5 + 6);
+    }
+
+    #[test]
+    fn add_with_registration() {
+        let mut manager = GearDataManager::new();
+        let gear_id = GearId::new(1).unwrap();
+        manager.add_registering(gear_id, &Datum { value: 7 });
+        manager.add_registering(gear_id, &Tag);
+        manager.add_registering(GearId::new(2).unwrap(), &Datum { value: 8 });
+        assert_eq!(manager.types.len(), 1);
+        assert_eq!(manager.tags.len(), 1);
+
+        let mut values = vec![];
+        manager
+            .iter()
+            .with_tags::<&Tag>()
+            .run(|(d,): (&Datum,)| values.push(d.value));
+        assert_eq!(values, [7]);
+    }
+
+    #[cfg(feature = "query-timings")]
+    #[test]
+    fn query_timings() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+        for i in 1..=4 {
+            let gear_id = GearId::new(i as u16).unwrap();
+            manager.add(gear_id, &Datum { value: i });
+            if i > 2 {
+                manager.add(gear_id, &Position { x: i, y: i });
+            }
+        }
+
+        manager.iter().run(|(_,): (&Datum,)| {});
+        let blocks: Vec<_> = manager
+            .last_query_timings()
+            .iter()
+            .map(|(block_index, _)| *block_index)
+            .collect();
+        assert_eq!(blocks, [0, 1]);
+
+        manager.iter().run(|(_,): (&Position,)| {});
+        assert_eq!(manager.last_query_timings().len(), 1);
+    }
+
+    #[test]
+    fn archetype_slices() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+        for i in 1..=3 {
+            let gear_id = GearId::new(i as u16).unwrap();
+            manager.add(gear_id, &Datum { value: i });
+            manager.add(gear_id, &Position { x: i, y: 0 });
+        }
+
+        for (ids, (data, positions)) in manager.archetype_slices::<(&mut Datum, &mut Position)>() {
+            assert_eq!(ids.len(), 3);
+            assert_eq!(data.len(), 3);
+            assert_eq!(positions.len(), 3);
+            for (datum, position) in data.iter_mut().zip(positions.iter_mut()) {
+                position.y = datum.value * 2;
+            }
+        }
+
+        let elements_count = manager.blocks[1].elements_count as usize;
+        let slices = manager.archetype_slices::<(&Datum, &Position)>();
+        assert_eq!(slices.len(), 1);
+        let (ids, (data, positions)) = &slices[0];
+        assert_eq!(ids.len(), elements_count);
+        for i in 0..ids.len() {
+            assert_eq!(positions[i].y, data[i].value * 2);
+            assert_eq!(positions[i].x, ids[i].get() as u32);
+        }
+    }
+
+    #[test]
+    fn register_after_population() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        for i in 1..=4 {
+            manager.add(GearId::new(i as u16).unwrap(), &Datum { value: i });
+        }
+
+        manager.register::<Position>();
+        for i in 1..=2 {
+            manager.add(GearId::new(i as u16).unwrap(), &Position { x: i, y: i });
+        }
+        manager.remove_all(GearId::new(1).unwrap());
+        manager.remove::<Datum>(GearId::new(2).unwrap());
+
+        let mut sum = 0;
+        manager.iter().run(|(d,): (&Datum,)| sum += d.value);
+        assert_eq!(sum, 7);
+        manager.iter().run_id(|gear_id, (p,): (&Position,)| {
+            assert_eq!(gear_id.get(), 2);
+            assert_eq!((p.x, p.y), (2, 2));
+        });
+        for block in &manager.blocks {
+            assert!(!format!("{:?}", block).is_empty());
+        }
+    }
+
+    #[test]
+    fn located_iteration() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+        for i in 1..=6 {
+            let gear_id = GearId::new(i as u16).unwrap();
+            manager.add(gear_id, &Datum { value: i });
+            if i % 2 == 0 {
+                manager.add(gear_id, &Position { x: i, y: i });
+            }
+        }
+        manager.remove_all(GearId::new(1).unwrap());
+
+        let mut count = 0;
+        let lookup = manager.lookup.clone();
+        manager.iter_located(|gear_id, block_index, index, (_,): (&Datum,)| {
+            let entry = lookup[gear_id.get() as usize - 1];
+            assert_eq!(entry.block_index, block_index);
+            assert_eq!(entry.index.unwrap().get() - 1, index);
+            count += 1;
+        });
+        assert_eq!(count, 5);
+    }
+
+    #[test]
+    fn registered_type_sizes() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+        manager.register::<u8>();
+        manager.register::<Tag>();
+
+        let types = [
+            (TypeId::of::<Datum>(), size_of::<Datum>()),
+            (TypeId::of::<Position>(), size_of::<Position>()),
+            (TypeId::of::<u8>(), size_of::<u8>()),
+        ];
+        assert_eq!(manager.registered_types().len(), types.len());
+        for ((type_id, size), registered) in types.iter().zip(manager.registered_types()) {
+            assert_eq!(type_id, registered);
+            assert_eq!(manager.component_size(*type_id), Some(*size as u16));
+        }
+        assert_eq!(manager.component_size(TypeId::of::<Tag>()), None);
+    }
+
+    #[test]
+    fn shared_iteration() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+        for i in 1..=4 {
+            let gear_id = GearId::new(i as u16).unwrap();
+            manager.add(gear_id, &Datum { value: i });
+            manager.add(gear_id, &Position { x: i, y: i });
+        }
+
+        let first = &manager;
+        let second = &manager;
+        let mut sum = 0;
+        first.iter_ref(|_, (d,): (&Datum,)| {
+            second.iter_ref(|_, (p, d2): (&Position, &Datum)| sum += d.value * p.x + d2.value);
+        });
+        assert_eq!(sum, 10 * 10 + 4 * 10);
+    }
+
+    #[test]
+    fn mask_ops() {
+        assert!(0u64.with_bit(3).has_bit(3) && !0u64.with_bit(3).has_bit(4));
+        assert!(0b1011u64.contains(&0b0011) && !0b1011u64.contains(&0b0111));
+        assert!(0b1011u64.intersects(&0b0110) && !0b1001u64.intersects(&0b0110));
+    }
+
+    #[test]
+    fn log_and_skip_policy() {
+        static REPORTS: AtomicUsize = AtomicUsize::new(0);
+
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.set_error_policy(ErrorPolicy::LogAndSkip(|_| {
+            REPORTS.fetch_add(1, Ordering::Relaxed);
+        }));
+
+        let gear_id = GearId::new(1).unwrap();
+        manager.add(gear_id, &Position { x: 0, y: 0 });
+        manager.add(gear_id, &Datum { value: 1 });
+        manager.add_tag::<Tag>(gear_id);
+        manager.remove::<Position>(gear_id);
+        manager.iter().run(|(_,): (&Position,)| unreachable!());
+        manager
+            .iter()
+            .run(|(_, _): (&Datum, &Datum)| unreachable!());
+        assert_eq!(REPORTS.load(Ordering::Relaxed), 5);
+
+        let mut sum = 0;
+        manager.iter().run(|(d,): (&Datum,)| sum += d.value);
+        assert_eq!(sum, 1);
+    }
+
+    #[test]
+    fn archetype_misuse_is_reported() {
+        static REPORTS: AtomicUsize = AtomicUsize::new(0);
+
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Tag>();
+        manager.set_error_policy(ErrorPolicy::LogAndSkip(|_| {
+            REPORTS.fetch_add(1, Ordering::Relaxed);
+        }));
+
+        assert_eq!(manager.ensure_archetype::<(&Position,)>(), None);
+        assert_eq!(manager.ensure_archetype::<(&Tag,)>(), None);
+        assert_eq!(manager.reserve_contiguous::<(&Datum, &Position)>(1), None);
+        assert_eq!(
+            manager.archetype_capacity::<(&Position,)>(),
+            Err(ArchetypeTooWide)
+        );
+        manager.pin_archetype::<(&Position,)>();
+        manager.set_recycling::<(&Position,)>(1);
+        manager.add_to_all::<(&Position,), _>(&Datum { value: 1 });
+        assert_eq!(REPORTS.load(Ordering::Relaxed), 7);
+        assert!(manager.blocks.is_empty());
+        assert!(manager.pinned.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "Unregistered type")]
+    fn panic_policy() {
+        let mut manager = GearDataManager::new();
+        manager.add(GearId::new(1).unwrap(), &Datum { value: 1 });
+    }
+
+    #[test]
+    fn fold_iteration() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        for i in 1..=10 {
+            manager.add(GearId::new(i as u16).unwrap(), &Datum { value: i });
+        }
+
+        let mut sum = 0;
+        manager.iter().run(|(d,): (&Datum,)| sum += d.value);
+        let folded = manager.fold_id(0, |acc, _, (d,): (&Datum,)| acc + d.value);
+        assert_eq!(folded, sum);
+
+        let max_id = manager.fold_id(None, |acc: Option<GearId>, gear_id, (_,): (&Datum,)| {
+            acc.max(Some(gear_id))
+        });
+        assert_eq!(max_id, GearId::new(10));
+    }
+
+    #[test]
+    fn recompute_pointers() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+        for i in 1..=4 {
+            let gear_id = GearId::new(i as u16).unwrap();
+            manager.add(gear_id, &Datum { value: i });
+            manager.add(gear_id, &Position { x: i, y: i });
+        }
+
+        let original: Vec<_> = manager
+            .blocks
+            .iter()
+            .map(|b| b.component_blocks.clone())
+            .collect();
+        for block in &mut manager.blocks {
+            block.component_blocks.swap(0, 1);
+            block.component_blocks[0] = Some(NonNull::dangling());
+            assert!(!block.pointers_valid());
+        }
+
+        manager.recompute_block_pointers();
+        for (block, pointers) in manager.blocks.iter().zip(original) {
+            assert!(block.pointers_valid());
+            assert_eq!(block.component_blocks, pointers);
+        }
+
+        let mut sum = 0;
+        manager
+            .iter()
+            .run(|(d, p): (&Datum, &Position)| sum += d.value + p.x);
+        assert_eq!(sum, 20);
+    }
+
+    #[test]
+    fn query_macro() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+        for i in 1..=4 {
+            let gear_id = GearId::new(i as u16).unwrap();
+            manager.add(gear_id, &Datum { value: i });
+            manager.add(gear_id, &Position { x: 0, y: 0 });
+        }
+
+        crate::query!(manager, (d: &Datum, p: &mut Position) => {
+            p.x = d.value * 2;
+        });
+        manager
+            .iter()
+            .run(|(d, p): (&mut Datum, &Position)| d.value += p.x);
+
+        let mut expected = 0;
+        manager.iter().run(|(d,): (&Datum,)| expected += d.value);
+        let mut sum = 0;
+        crate::query!(manager, gear_id, (p: &Position, d: &Datum) => {
+            assert_eq!(p.x * 3 / 2, d.value);
+            assert_eq!(d.value, gear_id.get() as u32 * 3);
+            sum += d.value;
+        });
+        assert_eq!(sum, expected);
+        assert_eq!(sum, 30);
+    }
+
+    #[test]
+    fn detach_and_attach() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+        let gear_id = GearId::new(1).unwrap();
+        manager.add(gear_id, &Datum { value: 1 });
+        manager.add(gear_id, &Position { x: 3, y: 4 });
+
+        let detached = manager.detach::<Position>(gear_id).unwrap();
+        assert_eq!(detached.value().x, 3);
+        assert!(manager.detach::<Position>(gear_id).is_none());
+        manager.iter().run(|(_,): (&Position,)| unreachable!());
+
+        manager.attach(gear_id, detached);
+        let mut count = 0;
+        manager.iter().run(|(p, d): (&Position, &Datum)| {
+            assert_eq!((p.x, p.y, d.value), (3, 4, 1));
+            count += 1;
+        });
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn large_component_offsets() {
+        #[derive(Clone)]
+        struct Large([u8; 16000]);
+
+        let mut manager = GearDataManager::new();
+        manager.register::<Large>();
+        manager.register::<Datum>();
+        for i in 1..=3 {
+            let gear_id = GearId::new(i).unwrap();
+            manager.add(gear_id, &Large([i as u8; 16000]));
+            manager.add(gear_id, &Datum { value: i as u32 });
+        }
+        assert_eq!(manager.blocks[0].max_elements, 2);
+
+        manager.remove::<Datum>(GearId::new(1).unwrap());
+        manager.remove_all(GearId::new(2).unwrap());
+
+        manager.iter().run_id(|gear_id, (l,): (&Large,)| {
+            assert!(l.0.iter().all(|b| *b == gear_id.get() as u8));
+        });
+        manager.iter().run_id(|gear_id, (l, d): (&Large, &Datum)| {
+            assert_eq!(d.value, 3);
+            assert!(l.0.iter().all(|b| *b == gear_id.get() as u8));
+        });
+    }
+
+    #[test]
+    fn raw_component_bytes() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<u32>();
+        for i in 1..=3 {
+            manager.add(GearId::new(i).unwrap(), &(0xDEAD_0000u32 + i as u32));
+        }
+        let component = manager.component_id::<u32>().unwrap();
+
+        let bytes = manager
+            .component_bytes(GearId::new(2).unwrap(), component)
+            .unwrap();
+        let mut value = [0; 4];
+        value.copy_from_slice(bytes);
+        assert_eq!(u32::from_ne_bytes(value), 0xDEAD_0002);
+
+        let block_bytes = manager.component_byte_slice(0, component);
+        assert_eq!(block_bytes.len(), 3 * 4);
+        assert_eq!(&block_bytes[4..8], bytes);
+
+        let datum_component = manager.component_id::<Datum>().unwrap();
+        assert!(manager
+            .component_bytes(GearId::new(2).unwrap(), datum_component)
+            .is_none());
+        assert!(manager.component_byte_slice(0, datum_component).is_empty());
+    }
+
+    #[test]
+    fn first_and_last_element() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+        assert!(manager.first_with(|_, (_,): (&Datum,)| ()).is_none());
+
+        let wind_gear = GearId::new(5).unwrap();
+        manager.add(wind_gear, &Position { x: 1, y: 2 });
+        let gear_id = manager.first_with(|gear_id, (p,): (&mut Position,)| {
+            p.x = 10;
+            gear_id
+        });
+        assert_eq!(gear_id, Some(wind_gear));
+        assert_eq!(
+            manager.last_with(|gear_id, (p,): (&Position,)| (gear_id, p.x)),
+            Some((wind_gear, 10))
+        );
+
+        for i in 1..=3 {
+            manager.add(GearId::new(i).unwrap(), &Datum { value: i as u32 });
+        }
+        assert_eq!(
+            manager.first_with(|gear_id, (_,): (&Datum,)| gear_id.get()),
+            Some(1)
+        );
+        assert_eq!(
+            manager.last_with(|gear_id, (_,): (&Datum,)| gear_id.get()),
+            Some(3)
+        );
+    }
+
+    #[test]
+    fn reserved_ids() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        let gear_id = GearId::new(7).unwrap();
+
+        assert_eq!(manager.reserve_id(gear_id), Ok(()));
+        assert_eq!(manager.reserve_id(gear_id), Err(IdInUse));
+
+        manager.add(gear_id, &Datum { value: 7 });
+        assert!(manager.contains::<Datum>(gear_id));
+        assert_eq!(manager.reserve_id(gear_id), Err(IdInUse));
+
+        manager.remove_all(gear_id);
+        assert_eq!(manager.reserve_id(gear_id), Ok(()));
+    }
+
+    #[test]
+    fn dot_export() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+        manager.register::<Tag>();
+
+        let gear_id = GearId::new(1).unwrap();
+        manager.add(gear_id, &Datum { value: 1 });
+        manager.add(gear_id, &Position { x: 0, y: 0 });
+        manager.add_tag::<Tag>(gear_id);
+        manager.add(GearId::new(2).unwrap(), &Position { x: 0, y: 0 });
+
+        let dot = manager.to_dot();
+        assert!(dot.starts_with("digraph"));
+        assert!(dot.contains("[label=\"C0\\n0/"));
+        assert!(dot.contains("[label=\"C0 C1\\n0/"));
+        assert!(dot.contains("[label=\"C0 C1 T0\\n1/"));
+        assert!(dot.contains("[label=\"C1\\n1/"));
+        assert!(dot.contains("a0 -> a1;"));
+        assert!(dot.contains("a1 -> a2;"));
+        assert_eq!(dot.matches("->").count(), 2);
+    }
+
+    #[test]
+    fn transition_tracking() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+        manager.register::<Tag>();
+
+        let gear_id = GearId::new(1).unwrap();
+        manager.add(gear_id, &Datum { value: 1 });
+        manager.set_track_transitions(true);
+        manager.add(gear_id, &Position { x: 0, y: 0 });
+        manager.add_tag::<Tag>(gear_id);
+        manager.remove::<Datum>(gear_id);
+        manager.remove_all(gear_id);
+
+        assert_eq!(
+            manager.drain_transitions(),
+            [
+                (gear_id, 0b01, 0b11),
+                (gear_id, 0b11, 0b11),
+                (gear_id, 0b11, 0b10),
+                (gear_id, 0b10, 0)
+            ]
+        );
+        assert!(manager.drain_transitions().is_empty());
+
+        manager.add(gear_id, &Datum { value: 1 });
+        assert_eq!(manager.drain_transitions(), [(gear_id, 0, 0b01)]);
+        manager.add_tag::<Tag>(gear_id);
+        manager.add_tag::<Tag>(gear_id);
+        manager.remove_from_all::<Tag>();
+        assert_eq!(
+            manager.drain_transitions(),
+            [(gear_id, 0b01, 0b01), (gear_id, 0b01, 0b01)]
+        );
+        manager.set_track_transitions(false);
+        manager.remove_all(gear_id);
+        assert!(manager.drain_transitions().is_empty());
+    }
+
+    #[test]
+    fn type_limit() {
+        let mut manager = GearDataManager::new();
+        register_numbered!(manager,
+            0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31
+            32 33 34 35 36 37 38 39 40 41 42 43 44 45 46 47 48 49 50 51 52 53 54 55 56 57 58 59
+            60 61 62 63);
+        assert_eq!(manager.try_register::<Numbered<5>>(), Ok(5));
+        assert_eq!(
+            manager.try_register::<Datum>(),
+            Err(ComponentError::TooManyTypes)
+        );
+        assert_eq!(manager.try_register::<Tag>(), Ok(0));
+        assert_eq!(manager.registered_types().len(), 64);
+
+        manager.add(GearId::new(1).unwrap(), &Numbered::<63>(1));
+        manager
+            .iter()
+            .run(|(n,): (&Numbered<63>,)| assert_eq!(n.0, 1));
+    }
+
+    #[test]
+    #[should_panic(expected = "Too many registered types")]
+    fn type_limit_panics() {
+        let mut manager = GearDataManager::new();
+        register_numbered!(manager,
+            0 1 2 3 4 5 6 7 8 9 10 11 12 13 14 15 16 17 18 19 20 21 22 23 24 25 26 27 28 29 30 31
+            32 33 34 35 36 37 38 39 40 41 42 43 44 45 46 47 48 49 50 51 52 53 54 55 56 57 58 59
+            60 61 62 63);
+        manager.register::<Datum>();
+    }
+
+    #[test]
+    fn ordered_removal() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        for i in 1..=6 {
+            manager.add(GearId::new(i as u16).unwrap(), &Datum { value: i * 10 });
+        }
+
+        manager.remove_ordered(GearId::new(3).unwrap());
+        manager.remove_ordered(GearId::new(1).unwrap());
+        manager.remove_ordered(GearId::new(6).unwrap());
+
+        let mut order = vec![];
+        manager.iter_located(|gear_id, _, index, (d,): (&Datum,)| {
+            assert_eq!(d.value, gear_id.get() as u32 * 10);
+            assert_eq!(index as usize, order.len());
+            order.push(gear_id.get());
+        });
+        assert_eq!(order, [2, 4, 5]);
+    }
+
+    #[test]
+    fn tuple_selector() {
+        let mut manager = GearDataManager::new();
+        manager.register::<u8>();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+
+        assert_eq!(manager.selector_for::<(&Datum,)>(), Some(0b010));
+        assert_eq!(manager.selector_for::<(&mut Position, &u8)>(), Some(0b101));
+        assert_eq!(manager.selector_for::<(&Datum, &u16)>(), None);
+        assert_eq!(manager.selector_for::<(&Datum, &Datum)>(), None);
+    }
+
+    #[test]
+    fn query_conflicts() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+        let datum = manager.selector_for::<(&Datum,)>().unwrap();
+        let position = manager.selector_for::<(&Position,)>().unwrap();
+
+        assert!(queries_conflict(datum, 0, 0, datum));
+        assert!(queries_conflict(0, datum, datum, 0));
+        assert!(queries_conflict(datum, 0, datum, 0));
+        assert!(!queries_conflict(0, datum, 0, datum));
+        assert!(!queries_conflict(datum, 0, position, 0));
+        assert!(!queries_conflict(datum, position, 0, position));
+    }
+
+    #[test]
+    fn prioritized_gear() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        for i in 1..=5 {
+            manager.add(GearId::new(i as u16).unwrap(), &Datum { value: i });
+        }
+        manager.prioritize(GearId::new(4).unwrap());
+
+        let mut order = vec![];
+        manager.iter_located(|gear_id, _, index, (d,): (&Datum,)| {
+            assert_eq!(d.value, gear_id.get() as u32);
+            order.push((index, gear_id.get()));
+        });
+        assert_eq!(order, [(0, 4), (1, 2), (2, 3), (3, 1), (4, 5)]);
+    }
+
+    #[test]
+    fn optional_iteration() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.add(GearId::new(1).unwrap(), &Datum { value: 1 });
+
+        let mut count = 0;
+        manager.iter_opt(|_, (_, _): (&Datum, &Position)| count += 1);
+        assert_eq!(count, 0);
+
+        manager.register::<Position>();
+        manager.add(GearId::new(1).unwrap(), &Position { x: 1, y: 1 });
+        manager.iter_opt(|_, (_, _): (&Datum, &Position)| count += 1);
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn disabled_components() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+        for i in 1..=3 {
+            let gear_id = GearId::new(i as u16).unwrap();
+            manager.add(gear_id, &Datum { value: i });
+            manager.add(gear_id, &Position { x: i, y: i });
+        }
+        let gear_id = GearId::new(2).unwrap();
+        manager.set_enabled::<Datum>(gear_id, false);
+
+        let mut sum = 0;
+        manager.iter_enabled(|_, (d,): (&Datum,)| sum += d.value);
+        assert_eq!(sum, 4);
+        sum = 0;
+        manager.iter_enabled(|_, (p,): (&Position,)| sum += p.x);
+        assert_eq!(sum, 6);
+
+        manager.remove_all(GearId::new(1).unwrap());
+        sum = 0;
+        manager.iter_enabled(|_, (d,): (&Datum,)| sum += d.value);
+        assert_eq!(sum, 3);
+
+        manager.set_enabled::<Datum>(gear_id, true);
+        sum = 0;
+        manager.iter_enabled(|_, (d,): (&Datum,)| sum += d.value);
+        assert_eq!(sum, 5);
+    }
+
+    #[test]
+    #[should_panic(expected = "Unregistered type hwphysics::data::test::Position")]
+    fn misuse_names_type() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.add(GearId::new(1).unwrap(), &Position { x: 1, y: 1 });
+    }
+
+    #[test]
+    #[should_panic(expected = "Unregistered type with id 1")]
+    fn misuse_names_component_id() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.add(GearId::new(1).unwrap(), &Datum { value: 1 });
+        manager.remove_by_id(GearId::new(1).unwrap(), 1);
+    }
+
+    #[test]
+    fn lockstep_iteration() {
+        let mut predicted = GearDataManager::new();
+        predicted.register::<Datum>();
+        let mut authoritative = GearDataManager::new();
+        authoritative.register::<Position>();
+        authoritative.register::<Datum>();
+
+        for i in 1..=4 {
+            let gear_id = GearId::new(i as u16).unwrap();
+            predicted.add(gear_id, &Datum { value: i });
+            if i != 4 {
+                authoritative.add(gear_id, &Datum { value: i });
+            }
+        }
+        authoritative.add(GearId::new(1).unwrap(), &Position { x: 0, y: 0 });
+        predicted.iter().run_id(|gear_id, (d,): (&mut Datum,)| {
+            if gear_id.get() == 2 {
+                d.value = 20;
+            }
+        });
+
+        let mut seen = vec![];
+        GearDataManager::zip_iter(
+            &predicted,
+            &authoritative,
+            |gear_id, (p,): (&Datum,), (a,): (&Datum,)| {
+                if p.value != a.value {
+                    seen.push((gear_id.get(), p.value, a.value));
+                }
+            },
+        );
+        assert_eq!(seen, [(2, 20, 2)]);
+    }
+
+    #[test]
+    fn upsert_component() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        let gear_id = GearId::new(1).unwrap();
+
+        manager.upsert(gear_id, &Datum { value: 1 }, |d| d.value += 10);
+        assert_eq!(manager.first_with(|_, (d,): (&Datum,)| d.value), Some(1));
+        manager.upsert(gear_id, &Datum { value: 1 }, |d| d.value += 10);
+        assert_eq!(manager.first_with(|_, (d,): (&Datum,)| d.value), Some(11));
+    }
+
+    #[test]
+    fn contiguous_reserve() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        let first_block = manager.ensure_archetype::<(&Datum,)>().unwrap();
+        let capacity = manager.blocks[first_block as usize].max_elements;
+        for i in 1..=capacity - 10 {
+            manager.add(GearId::new(i).unwrap(), &Datum { value: 0 });
+        }
+        let first_location = manager.locate(GearId::new(1).unwrap());
+
+        let block_index = manager.reserve_contiguous::<(&Datum,)>(100).unwrap();
+        assert_ne!(block_index, first_block);
+        assert_eq!(manager.locate(GearId::new(1).unwrap()), first_location);
+        for i in capacity + 1..=capacity + 100 {
+            manager.add(GearId::new(i).unwrap(), &Datum { value: 1 });
+        }
+
+        let mut count = 0;
+        manager.iter_located(|_, located_index, _, (d,): (&Datum,)| {
+            if d.value == 1 {
+                assert_eq!(located_index, block_index);
+                count += 1;
+            } else {
+                assert_ne!(located_index, block_index);
+            }
+        });
+        assert_eq!(count, 100);
+        assert_eq!(manager.reserve_contiguous::<(&Datum,)>(capacity + 1), None);
+    }
+
+    #[test]
+    fn mask_type_names() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Tag>();
+        manager.register::<Position>();
+        let mask = manager.selector_for::<(&Position, &Datum)>().unwrap();
+
+        assert_eq!(
+            manager.mask_to_type_names(mask),
+            [
+                "hwphysics::data::test::Datum",
+                "hwphysics::data::test::Position"
+            ]
+        );
+        assert!(manager.mask_to_type_names(0).is_empty());
+    }
+
+    #[test]
+    fn add_without_allocation() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        let gear_id = GearId::new(1).unwrap();
+        assert_eq!(
+            manager.try_add_no_alloc(gear_id, &Datum { value: 0 }),
+            Err(OutOfBlocks)
+        );
+
+        let block_index = manager.ensure_archetype::<(&Datum,)>().unwrap();
+        let capacity = manager.blocks[block_index as usize].max_elements;
+        for i in 1..=capacity {
+            let gear_id = GearId::new(i).unwrap();
+            assert_eq!(
+                manager.try_add_no_alloc(gear_id, &Datum { value: 0 }),
+                Ok(())
+            );
+        }
+        assert_eq!(
+            manager.try_add_no_alloc(GearId::new(capacity + 1).unwrap(), &Datum { value: 0 }),
+            Err(OutOfBlocks)
+        );
+        assert_eq!(manager.blocks.len(), 1);
+    }
+
+    #[test]
+    fn external_join() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        for i in 1..=5 {
+            manager.add(GearId::new(i as u16).unwrap(), &Datum { value: i });
+        }
+        let mut names = HashMap::new();
+        names.insert(GearId::new(2).unwrap(), "two");
+        names.insert(GearId::new(4).unwrap(), "four");
+        names.insert(GearId::new(7).unwrap(), "seven");
+
+        let mut visited = vec![];
+        manager.iter_join(&names, |gear_id, (d,): (&Datum,), name| {
+            assert_eq!(gear_id.get() as u32, d.value);
+            visited.push(*name);
+        });
+        visited.sort();
+        assert_eq!(visited, ["four", "two"]);
+    }
+
+    #[test]
+    fn gear_location() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        for i in 1..=3 {
+            manager.add(GearId::new(i as u16).unwrap(), &Datum { value: i });
+        }
+        let gear_id = GearId::new(3).unwrap();
+        assert_eq!(manager.locate(gear_id), Some((0, 2)));
+
+        manager.remove_all(GearId::new(1).unwrap());
+        assert_eq!(manager.locate(gear_id), Some((0, 0)));
+        assert_eq!(manager.locate(GearId::new(1).unwrap()), None);
+    }
+
+    #[derive(Clone)]
+    struct Large {
+        values: [u64; 1024],
+        token: Rc<()>,
+    }
+
+    #[test]
+    fn boxed_components() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register_boxed::<Large>();
+        let token = Rc::new(());
+        let mut large = Large {
+            values: [0; 1024],
+            token: token.clone(),
+        };
+
+        for i in 1..=3 {
+            large.values[1023] = i;
+            let gear_id = GearId::new(i as u16).unwrap();
+            manager.add(gear_id, &Datum { value: i as u32 });
+            manager.add(gear_id, &Boxed::new(large.clone()));
+        }
+        let block_index = manager.locate(GearId::new(1).unwrap()).unwrap().0;
+        assert!(manager.blocks[block_index as usize].max_elements > 1000);
+        assert_eq!(Rc::strong_count(&token), 5);
+
+        manager.iter().run(|(d, l): (&Datum, &mut Boxed<Large>)| {
+            assert_eq!(l.values[1023], d.value as u64);
+            l.values[0] = 1;
+        });
+        assert_eq!(
+            manager.first_with(|_, (l,): (&Boxed<Large>,)| l.values[0]),
+            Some(1)
+        );
+
+        manager.remove::<Boxed<Large>>(GearId::new(1).unwrap());
+        manager.remove_all(GearId::new(2).unwrap());
+        assert_eq!(Rc::strong_count(&token), 3);
+        drop(manager);
+        assert_eq!(Rc::strong_count(&token), 2);
+        assert_eq!(Rc::strong_count(&large.token), 2);
+    }
+
+    #[test]
+    fn populated_range() {
+        let mut manager = GearDataManager::new();
+        manager.populate_range(1..10001, |id| Datum { value: id as u32 });
+
+        let mut count = 0;
+        manager.iter().run_id(|gear_id, (d,): (&Datum,)| {
+            assert_eq!(d.value, gear_id.get() as u32);
+            count += 1;
+        });
+        assert_eq!(count, 10000);
+        assert_eq!(manager.blocks.len(), 2);
+    }
+
+    #[test]
+    fn raw_add() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+        let datum = manager.component_id::<Datum>().unwrap();
+        let position = manager.component_id::<Position>().unwrap();
+
+        assert_eq!(
+            manager.add_raw(0, datum, &5u32.to_ne_bytes()),
+            Err(RawAddError::InvalidGearId)
+        );
+        assert_eq!(
+            manager.add_raw(1, 2, &5u32.to_ne_bytes()),
+            Err(RawAddError::UnregisteredComponent)
+        );
+        assert_eq!(
+            manager.add_raw(1, position, &5u32.to_ne_bytes()),
+            Err(RawAddError::SizeMismatch)
+        );
+
+        assert_eq!(manager.add_raw(1, datum, &5u32.to_ne_bytes()), Ok(()));
+        assert_eq!(manager.add_raw(1, position, &[0; 8]), Ok(()));
+        assert_eq!(manager.add_raw(1, datum, &7u32.to_ne_bytes()), Ok(()));
+        assert_eq!(
+            manager.first_with(|gear_id, (d, p): (&Datum, &Position)| (
+                gear_id.get(),
+                d.value,
+                p.x,
+                p.y
+            )),
+            Some((1, 7, 0, 0))
+        );
+    }
+
+    #[test]
+    fn incremental_compaction() {
+        fn fragmented_manager() -> GearDataManager {
+            let mut manager = GearDataManager::new();
+            manager.register::<Datum>();
+            manager.register::<Position>();
+            for i in 1..=8000 {
+                let gear_id = GearId::new(i).unwrap();
+                manager.add(gear_id, &Datum { value: i as u32 });
+                if i % 1000 == 0 {
+                    manager.add(gear_id, &Position { x: i as u32, y: 0 });
+                }
+            }
+            for i in (1..=3000).step_by(2) {
+                manager.remove_all(GearId::new(i).unwrap());
+            }
+            manager
+        }
+        fn layout(manager: &GearDataManager) -> Vec<Vec<GearId>> {
+            manager
+                .blocks
+                .iter()
+                .map(|b| b.gear_ids()[0..b.elements_count as usize].to_vec())
+                .collect()
+        }
+
+        let mut full = fragmented_manager();
+        full.consolidate();
+        assert_eq!(
+            full.blocks.iter().filter(|b| b.elements_count > 0).count(),
+            3
+        );
+        assert!(full.blocks[0].is_full());
+
+        let mut incremental = fragmented_manager();
+        let mut steps = 0;
+        while incremental.compact_step(100) {
+            steps += 1;
+        }
+        assert!(steps > 1);
+        assert_eq!(layout(&incremental), layout(&full));
+
+        let mut sum = 0;
+        incremental.iter().run_id(|gear_id, (d,): (&Datum,)| {
+            assert_eq!(d.value, gear_id.get() as u32);
+            sum += 1;
+        });
+        assert_eq!(sum, 6500);
+    }
+
+    #[derive(Clone, Copy)]
+    struct Height(f32);
+
+    impl From<Height> for f64 {
+        fn from(height: Height) -> f64 {
+            height.0 as f64
+        }
+    }
+
+    #[test]
+    fn numeric_stats() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Height>();
+        manager.register::<Datum>();
+        assert_eq!(manager.component_stats::<Height>(), None);
+
+        for (i, height) in [2.0, -1.0, 5.0, 2.0].iter().enumerate() {
+            let gear_id = GearId::new(i as u16 + 1).unwrap();
+            manager.add(gear_id, &Height(*height));
+            if i % 2 == 0 {
+                manager.add(gear_id, &Datum { value: 0 });
+            }
+        }
+
+        assert_eq!(
+            manager.component_stats::<Height>(),
+            Some(Stats {
+                count: 4,
+                min: -1.0,
+                max: 5.0,
+                mean: 2.0
+            })
+        );
+    }
+
+    #[test]
+    fn rolled_back_transaction() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Tag>();
+        let gear_id = GearId::new(1).unwrap();
+        manager.add(gear_id, &Datum { value: 1 });
+
+        let result = manager.transaction(|t| {
+            t.remove::<Datum>(gear_id);
+            t.add(GearId::new(2).unwrap(), &Datum { value: 2 });
+            t.add(gear_id, &Position { x: 0, y: 0 });
+        });
+        assert!(result.unwrap_err().reason.contains("Position"));
+        assert!(manager.contains::<Datum>(gear_id));
+        assert_eq!(manager.locate(GearId::new(2).unwrap()), None);
+
+        let result = manager.transaction(|t| {
+            t.add(GearId::new(2).unwrap(), &Datum { value: 2 });
+            t.remove::<Datum>(gear_id);
+            t.add_tag::<Tag>(gear_id);
+        });
+        assert!(result.is_err());
+        assert!(manager.contains::<Datum>(gear_id));
+        assert_eq!(manager.locate(GearId::new(2).unwrap()), None);
+
+        let result = manager.transaction(|t| {
+            t.add(GearId::new(2).unwrap(), &Datum { value: 2 });
+            t.add_tag::<Tag>(GearId::new(2).unwrap());
+            t.remove::<Datum>(gear_id);
+        });
+        assert_eq!(result, Ok(()));
+        assert!(!manager.contains::<Datum>(gear_id));
+        let mut count = 0;
+        manager
+            .iter()
+            .with_tags::<&Tag>()
+            .run(|(_,): (&Datum,)| count += 1);
+        assert_eq!(count, 1);
+    }
+
+    #[test]
+    fn transaction_checks_archetype_capacity() {
+        #[derive(Clone)]
+        struct Wide<const N: usize> {
+            _bytes: [u8; 20000],
+        }
+
+        let mut manager = GearDataManager::new();
+        manager.register::<Wide<0>>();
+        manager.register::<Wide<1>>();
+        let gear_id = GearId::new(1).unwrap();
+
+        let result = manager.transaction(|t| {
+            t.add(gear_id, &Wide::<0> { _bytes: [0; 20000] });
+            t.add(gear_id, &Wide::<1> { _bytes: [0; 20000] });
+        });
+        assert!(result.is_err());
+        assert!(manager.blocks.is_empty());
+
+        let result = manager.transaction(|t| {
+            t.add(gear_id, &Wide::<0> { _bytes: [0; 20000] });
+            t.remove::<Wide<0>>(gear_id);
+            t.add(gear_id, &Wide::<1> { _bytes: [0; 20000] });
+        });
+        assert_eq!(result, Ok(()));
+        assert!(manager.contains::<Wide<1>>(gear_id));
+        assert!(!manager.contains::<Wide<0>>(gear_id));
+    }
+
+    #[test]
+    fn single_block_iteration() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+        for i in 1..=6 {
+            let gear_id = GearId::new(i as u16).unwrap();
+            manager.add(gear_id, &Datum { value: i });
+            if i % 2 == 0 {
+                manager.add(gear_id, &Position { x: i, y: i });
+            }
+        }
+
+        let block_index = manager.locate(GearId::new(2).unwrap()).unwrap().0;
+        let mut visited = vec![];
+        manager.iter_block(block_index, |gear_id, (_,): (&Datum,)| {
+            visited.push(gear_id.get())
+        });
+        visited.sort();
+        assert_eq!(visited, [2, 4, 6]);
+
+        let datum_block_index = manager.locate(GearId::new(1).unwrap()).unwrap().0;
+        manager.iter_block(datum_block_index, |_, (_,): (&Position,)| unreachable!());
+        manager.iter_block(100, |_, (_,): (&Datum,)| unreachable!());
+    }
+
+    #[test]
+    fn distinct_archetypes() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+        manager.register::<Tag>();
+        assert_eq!(manager.archetype_count(), 0);
+
+        manager.populate_range(1..8001, |id| Datum { value: id as u32 });
+        for i in 1..=6000 {
+            manager.add(GearId::new(i).unwrap(), &Position { x: 0, y: 0 });
+        }
+        manager.add_tag::<Tag>(GearId::new(1).unwrap());
+        assert!(manager.blocks.len() > 4);
+        assert_eq!(manager.archetype_count(), 3);
+    }
+
+    #[test]
+    #[cfg_attr(debug_assertions, should_panic(expected = "more than one block"))]
+    fn single_block_hint() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+        for i in 1..=5 {
+            manager.add(GearId::new(i as u16).unwrap(), &Datum { value: i });
+        }
+
+        let mut expected = vec![];
+        manager
+            .iter()
+            .run_id(|gear_id, (d,): (&Datum,)| expected.push((gear_id, d.value)));
+        let mut hinted = vec![];
+        manager.iter_hint_single(|gear_id, (d,): (&Datum,)| hinted.push((gear_id, d.value)));
+        assert_eq!(hinted, expected);
+
+        manager.add(GearId::new(1).unwrap(), &Position { x: 0, y: 0 });
+        let mut count = 0;
+        manager.iter_hint_single(|_, (_,): (&Datum,)| count += 1);
+        assert_eq!(count, 5);
+    }
+
+    #[test]
+    fn bundle_copy() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+        manager.register::<Tag>();
+        let gear_id = GearId::new(1).unwrap();
+        manager.add(gear_id, &Datum { value: 7 });
+        manager.add(gear_id, &Position { x: 3, y: 4 });
+        manager.add_tag::<Tag>(gear_id);
+        assert!(manager.extract(GearId::new(2).unwrap()).is_none());
+
+        let bundle = manager.extract(gear_id).unwrap();
+        let copy_id = GearId::new(2).unwrap();
+        manager.spawn_bundle(copy_id, &bundle).unwrap();
+        manager.remove_all(gear_id);
+
+        let mut copies = vec![];
+        manager
+            .iter()
+            .with_tags::<&Tag>()
+            .run_id(|gear_id, (d, p): (&Datum, &Position)| {
+                copies.push((gear_id, d.value, p.x, p.y))
+            });
+        assert_eq!(copies, [(copy_id, 7, 3, 4)]);
+    }
+
+    #[test]
+    fn iteration_with_resource() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        for i in 1..=3 {
+            manager.add(GearId::new(i as u16).unwrap(), &Datum { value: i });
+        }
+
+        let mut events = vec![];
+        manager.iter_id_with(&mut events, |events, gear_id, (d,): (&mut Datum,)| {
+            d.value *= 2;
+            events.push(gear_id.get());
+        });
+        events.sort();
+        assert_eq!(events, [1, 2, 3]);
+        assert_eq!(
+            manager.fold_id(0, |sum, _, (d,): (&Datum,)| sum + d.value),
+            12
+        );
+    }
+
+    #[test]
+    fn merged_blocks() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+        manager.populate_range(1..10001, |id| Datum { value: id as u32 });
+        manager.add(GearId::new(1).unwrap(), &Position { x: 0, y: 0 });
+        manager.remove::<Position>(GearId::new(1).unwrap());
+        for i in 2..=6000 {
+            manager.remove_all(GearId::new(i).unwrap());
+        }
+        assert_eq!(manager.blocks.len(), 3);
+
+        manager.shrink_to_fit();
+        assert_eq!(manager.blocks.len(), 1);
+        assert_eq!(manager.archetype_count(), 1);
+
+        let mut count = 0;
+        manager.iter_located(|gear_id, block_index, index, (d,): (&Datum,)| {
+            assert_eq!(d.value, gear_id.get() as u32);
+            assert_eq!((block_index, index), (0, count));
+            count += 1;
+        });
+        assert_eq!(count, 4001);
+    }
+
+    #[test]
+    fn unregister_keeps_archetypes_distinct() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+        let gear_id = GearId::new(1).unwrap();
+        manager.add(gear_id, &Datum { value: 1 });
+        manager.add(gear_id, &Position { x: 0, y: 0 });
+        manager.remove::<Position>(gear_id);
+        assert_eq!(manager.blocks.len(), 2);
+
+        assert_eq!(manager.unregister::<Position>(), Ok(()));
+        assert_eq!(manager.blocks.len(), 1);
+        assert_eq!(manager.archetype_count(), 1);
+        manager.shrink_to_fit();
+        assert_eq!(manager.blocks.len(), 1);
+        assert!(manager.contains::<Datum>(gear_id));
+    }
+
+    #[test]
+    fn unregister_type() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Tag>();
+        manager.register::<Position>();
+        manager.register::<Numbered<0>>();
+        let gear_id = GearId::new(1).unwrap();
+        manager.add(gear_id, &Datum { value: 1 });
+        manager.add(gear_id, &Position { x: 2, y: 3 });
+        manager.add(gear_id, &Numbered::<0>(4));
+        manager.add_tag::<Tag>(gear_id);
+
+        assert_eq!(manager.unregister::<Datum>(), Err(TypeInUse));
+        assert_eq!(manager.unregister::<Tag>(), Err(TypeInUse));
+        manager.remove::<Datum>(gear_id);
+        assert_eq!(manager.unregister::<Datum>(), Ok(()));
+
+        assert_eq!(manager.component_id::<Datum>(), None);
+        assert_eq!(manager.component_id::<Position>(), Some(1));
+        assert_eq!(manager.component_id::<Numbered<0>>(), Some(2));
+        assert_eq!(manager.registered_types().len(), 3);
+        assert_eq!(
+            manager.add_raw(1, 0, &[0; 4]),
+            Err(RawAddError::UnregisteredComponent)
+        );
+        assert_eq!(manager.component_bytes(gear_id, 0), None);
+
+        let mut count = 0;
+        manager
+            .iter()
+            .with_tags::<&Tag>()
+            .run(|(p, n): (&Position, &Numbered<0>)| {
+                assert_eq!((p.x, p.y, n.0), (2, 3, 4));
+                count += 1;
+            });
+        assert_eq!(count, 1);
+
+        manager.register::<Datum>();
+        assert_eq!(manager.component_id::<Datum>(), Some(0));
+        assert_eq!(manager.component_id::<Numbered<0>>(), Some(2));
+        manager.add(gear_id, &Datum { value: 5 });
+        assert_eq!(manager.first_with(|_, (d,): (&Datum,)| d.value), Some(5));
+        assert_eq!(
+            manager.component_bytes(gear_id, 1),
+            Some(&[2, 0, 0, 0, 3, 0, 0, 0][..])
+        );
+    }
+
+    #[test]
+    fn pair_iteration() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+        for i in 1..=10 {
+            manager.add(GearId::new(i as u16).unwrap(), &Datum { value: i });
+        }
+        manager.add(GearId::new(1).unwrap(), &Position { x: 0, y: 0 });
+
+        let mut count = 0;
+        manager.iter_pairs(|(a, (x,)): (GearId, (&Datum,)), (b, (y,))| {
+            assert_ne!(a, b);
+            assert_ne!(x.value, y.value);
+            count += 1;
+        });
+        assert_eq!(count, 9 * 8 / 2);
+    }
+
+    #[test]
+    fn layout_independent_hash() {
+        let mut first = GearDataManager::new();
+        first.register::<Datum>();
+        first.register::<Position>();
+        let mut second = GearDataManager::new();
+        second.register::<Datum>();
+        second.register::<Position>();
+
+        for i in 1..=10 {
+            first.add(GearId::new(i).unwrap(), &Datum { value: i as u32 });
+        }
+        for i in (1..=10).rev() {
+            second.add(GearId::new(i).unwrap(), &Datum { value: i as u32 });
+        }
+        first.add(GearId::new(3).unwrap(), &Position { x: 1, y: 2 });
+        second.add(GearId::new(3).unwrap(), &Position { x: 1, y: 2 });
+        assert_ne!(
+            first.locate(GearId::new(1).unwrap()),
+            second.locate(GearId::new(1).unwrap())
+        );
+        assert_eq!(first.state_hash(), second.state_hash());
+
+        second.iter().run(|(d,): (&mut Datum,)| d.value += 1);
+        assert_ne!(first.state_hash(), second.state_hash());
+    }
+
+    #[test]
+    fn registration_independent_hash() {
+        let mut first = GearDataManager::new();
+        first.register::<Datum>();
+        first.register::<Position>();
+        first.register::<Tag>();
+        let mut second = GearDataManager::new();
+        second.register::<Tag>();
+        second.register::<Position>();
+        second.register::<Datum>();
+
+        for manager in [&mut first, &mut second] {
+            let gear_id = GearId::new(1).unwrap();
+            manager.add(gear_id, &Datum { value: 1 });
+            manager.add(gear_id, &Position { x: 2, y: 3 });
+            manager.add_tag::<Tag>(gear_id);
+        }
+        assert_ne!(
+            first.component_id::<Datum>(),
+            second.component_id::<Datum>()
+        );
+        assert_eq!(first.state_hash(), second.state_hash());
+
+        second.remove::<Position>(GearId::new(1).unwrap());
+        assert_ne!(first.state_hash(), second.state_hash());
+    }
+
+    #[test]
+    fn partitioned_iteration() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+        for i in 1..=9 {
+            let gear_id = GearId::new(i as u16).unwrap();
+            manager.add(gear_id, &Datum { value: i });
+            if i % 3 == 0 {
+                manager.add(gear_id, &Position { x: i, y: i });
+            }
+        }
+
+        let mut visits = [0; 9];
+        for partition in 0..2 {
+            manager.iter_partition(partition, 2, |gear_id, (_,): (&mut Datum,)| {
+                visits[gear_id.get() as usize - 1] += 1
+            });
+        }
+        assert_eq!(visits, [1; 9]);
+    }
+
+    #[derive(Clone)]
+    struct Named {
+        _name: String,
+    }
+
+    #[repr(align(512))]
+    struct OverAligned {
+        _value: u8,
+    }
+
+    #[test]
+    fn checked_registration() {
+        const _: () = assert!(is_valid_component::<Datum>());
+        assert!(!is_valid_component::<Named>());
+        assert!(!is_valid_component::<[u8; 70000]>());
+
+        let mut manager = GearDataManager::new();
+        assert_eq!(manager.register_checked::<Datum>(), Ok(0));
+        assert_eq!(
+            manager.register_checked::<Named>(),
+            Err(ComponentError::NeedsDrop)
+        );
+        assert_eq!(
+            manager.register_checked::<[u8; 70000]>(),
+            Err(ComponentError::TooLarge)
+        );
+        assert_eq!(
+            manager.try_register::<[u8; 70000]>(),
+            Err(ComponentError::TooLarge)
+        );
+        assert_eq!(
+            manager.try_register::<OverAligned>(),
+            Err(ComponentError::BadAlign)
+        );
+        assert_eq!(manager.registered_types().len(), 1);
+    }
+
+    #[test]
+    fn component_slots() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Tag>();
+        manager.add(GearId::new(1).unwrap(), &Datum { value: 1 });
+        assert_eq!(manager.blocks[0].component_blocks.len(), 1);
+
+        manager.register::<Position>();
+        manager.register_boxed::<Numbered<0>>();
+        manager.add(GearId::new(2).unwrap(), &Position { x: 0, y: 0 });
+        for block in &manager.blocks {
+            assert_eq!(block.component_blocks.len(), 3);
+            assert!(block.pointers_valid());
+        }
+
+        manager.remove_all(GearId::new(1).unwrap());
+        manager.unregister::<Datum>().unwrap();
+        for block in &manager.blocks {
+            assert_eq!(block.component_blocks.len(), 3);
+            assert!(block.component_blocks[0].is_none());
+        }
+        manager.add(GearId::new(2).unwrap(), &Boxed::new(Numbered::<0>(3)));
+        let value = manager.first_with(|_, (_, boxed): (&Position, &Boxed<Numbered<0>>)| {
+            let numbered: &Numbered<0> = boxed;
+            numbered.0
+        });
+        assert_eq!(value, Some(3));
+    }
+
+    #[derive(Clone, Copy)]
+    struct Velocity(i32);
+
+    #[test]
+    fn copied_iteration() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Velocity>();
+        manager.register::<Datum>();
+        for i in 1..=4 {
+            let gear_id = GearId::new(i as u16).unwrap();
+            manager.add(gear_id, &Velocity(i));
+            if i % 2 == 0 {
+                manager.add(gear_id, &Datum { value: 0 });
+            }
+        }
+
+        let mut velocities = HashMap::new();
+        manager.iter_copied(|gear_id, (v,): (Velocity,)| {
+            velocities.insert(gear_id, v);
+        });
+        manager.remove_all(GearId::new(1).unwrap());
+        assert_eq!(velocities.values().map(|v| v.0).sum::<i32>(), 10);
+    }
+
+    #[test]
+    fn lookup_capacity() {
+        let mut manager = GearDataManager::with_capacity(4);
+        manager.register::<Datum>();
+        assert_eq!(manager.lookup.len(), 4);
+        #[cfg(not(feature = "strict"))]
+        {
+            assert!(!manager.contains::<Datum>(GearId::new(100).unwrap()));
+            manager.remove_all(GearId::new(100).unwrap());
+        }
+
+        manager.add(GearId::new(4).unwrap(), &Datum { value: 4 });
+        assert_eq!(manager.lookup.len(), 4);
+        manager.add(GearId::new(10).unwrap(), &Datum { value: 10 });
+        assert_eq!(manager.lookup.len(), 10);
+        assert_eq!(manager.reserve_id(GearId::new(u16::MAX).unwrap()), Ok(()));
+        assert_eq!(manager.lookup.len(), u16::MAX as usize);
+        assert_eq!(
+            manager.fold_id(0, |sum, _, (d,): (&Datum,)| sum + d.value),
+            14
+        );
+    }
+
+    #[test]
+    fn carried_components() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Velocity>();
+        manager.register::<Position>();
+        let gear_id = GearId::new(1).unwrap();
+        assert!(manager.component_ids_of(gear_id).is_empty());
+
+        manager.add(gear_id, &Position { x: 0, y: 0 });
+        manager.add(gear_id, &Datum { value: 0 });
+        manager.add(gear_id, &Velocity(0));
+        let expected: Vec<_> = [
+            manager.component_id::<Datum>(),
+            manager.component_id::<Velocity>(),
+            manager.component_id::<Position>(),
+        ]
+        .iter()
+        .map(|id| id.unwrap())
+        .collect();
+        assert_eq!(manager.component_ids_of(gear_id), expected);
+    }
+
+    #[test]
+    fn block_boundaries() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+        let block_index = manager.ensure_archetype::<(&Datum, &Position)>().unwrap();
+        let capacity = manager.blocks[block_index as usize].max_elements;
+
+        for round in 0..3u32 {
+            for i in 1..=capacity * 2 + 1 {
+                let gear_id = GearId::new(i).unwrap();
+                manager.add(gear_id, &Datum { value: round });
+                manager.add(gear_id, &Position { x: 0, y: 0 });
+            }
+            for block in &manager.blocks {
+                assert!(block.elements_count <= block.max_elements);
+                assert_eq!(
+                    block.disabled_components.len(),
+                    block.elements_count as usize
+                );
+            }
+            assert_eq!(
+                manager.fold_id(0, |count, _, (_, _): (&Datum, &Position)| count + 1),
+                capacity as u32 * 2 + 1
+            );
+            for i in 1..=capacity * 2 + 1 {
+                manager.remove::<Position>(GearId::new(i).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn added_since_marker() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+        for i in 1..=3 {
+            manager.add(GearId::new(i as u16).unwrap(), &Datum { value: i });
+        }
+        let marker = manager.mark();
+        manager.add(GearId::new(4).unwrap(), &Datum { value: 4 });
+        manager.add(GearId::new(5).unwrap(), &Datum { value: 5 });
+        manager.add(GearId::new(1).unwrap(), &Position { x: 0, y: 0 });
+        manager.add(GearId::new(4).unwrap(), &Position { x: 0, y: 0 });
+        manager.remove_all(GearId::new(2).unwrap());
+
+        let mut added = vec![];
+        manager.iter_added_since(marker, |gear_id, (_,): (&Datum,)| added.push(gear_id.get()));
+        added.sort();
+        assert_eq!(added, [4, 5]);
+
+        let marker = manager.mark();
+        manager.iter_added_since(marker, |_, (_,): (&Datum,)| unreachable!());
+    }
+
+    #[cfg(feature = "strict")]
+    #[test]
+    #[should_panic(expected = "Gear id 9 is out of the lookup range 1..=8")]
+    fn strict_lookup() {
+        let mut manager = GearDataManager::with_capacity(8);
+        manager.register::<Datum>();
+        manager.add(GearId::new(8).unwrap(), &Datum { value: 0 });
+        manager.contains::<Datum>(GearId::new(9).unwrap());
+    }
+
+    #[test]
+    fn open_block_tracking() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        let block_index = manager.ensure_archetype::<(&Datum,)>().unwrap();
+        let mask = manager.block_masks[block_index as usize];
+        let capacity = manager.blocks[block_index as usize].max_elements;
+
+        for i in 1..=capacity * 3 {
+            manager.add(GearId::new(i).unwrap(), &Datum { value: 0 });
+            let open = manager.open_blocks[&mask];
+            assert_eq!(open, (i - 1) / capacity);
+            assert_eq!(manager.locate(GearId::new(i).unwrap()).unwrap().0, open);
+        }
+
+        manager.remove_all(GearId::new(1).unwrap());
+        manager.add(GearId::new(capacity * 3 + 1).unwrap(), &Datum { value: 0 });
+        assert_eq!(manager.open_blocks[&mask], 0);
+        assert_eq!(manager.blocks.len(), 3);
+    }
+
+    #[test]
+    fn component_type_order() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Position>();
+        manager.register::<Tag>();
+        manager.register::<Velocity>();
+        manager.register::<Datum>();
+
+        let mut types = vec![];
+        manager.for_each_component_type(|id, type_id, size| types.push((id, type_id, size)));
+        assert_eq!(
+            types,
+            [
+                (0, TypeId::of::<Position>(), 8),
+                (1, TypeId::of::<Velocity>(), 4),
+                (2, TypeId::of::<Datum>(), 4)
+            ]
+        );
+    }
+
+    struct Fragile(u32);
+
+    impl Clone for Fragile {
+        fn clone(&self) -> Self {
+            if self.0 == 0 {
+                panic!("Clone failed")
+            }
+            Fragile(self.0)
+        }
+    }
+
+    #[test]
+    fn panicking_clone() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Fragile>();
+        let gear_id = GearId::new(1).unwrap();
+        manager.add(gear_id, &Datum { value: 1 });
+        manager.add(GearId::new(2).unwrap(), &Fragile(1));
+
+        let result = catch_unwind(AssertUnwindSafe(|| manager.add(gear_id, &Fragile(0))));
+        assert!(result.is_err());
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            manager.add(GearId::new(3).unwrap(), &Fragile(0))
+        }));
+        assert!(result.is_err());
+
+        assert_eq!(manager.validate(), Ok(()));
+        assert_eq!(manager.component_ids_of(gear_id), [0]);
+        assert_eq!(manager.locate(GearId::new(3).unwrap()), None);
+        assert_eq!(manager.fold_id(0, |sum, _, (f,): (&Fragile,)| sum + f.0), 1);
+    }
+
+    #[derive(Default)]
+    struct CountingVisitor {
+        visits: usize,
+        datum_sum: u32,
+    }
+
+    impl ComponentVisitor for CountingVisitor {
+        fn visit(&mut self, _gear_id: GearId, _component: ComponentId, value: &dyn Any) {
+            self.visits += 1;
+            if let Some(datum) = value.downcast_ref::<Datum>() {
+                self.datum_sum += datum.value;
+            }
+        }
+    }
+
+    #[test]
+    fn visit_components() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Tag>();
+        manager.register::<Position>();
+        manager.register::<Velocity>();
+        for i in 1..=6 {
+            let gear_id = GearId::new(i as u16).unwrap();
+            manager.add(gear_id, &Datum { value: i });
+            if i % 2 == 0 {
+                manager.add(gear_id, &Position { x: i, y: i });
+                manager.add_tag::<Tag>(gear_id);
+            }
+            if i % 3 == 0 {
+                manager.add(gear_id, &Velocity(0));
+            }
+        }
+
+        let mut visitor = CountingVisitor::default();
+        manager.visit_all(&mut visitor);
+        assert_eq!(visitor.visits, 6 + 3 + 2);
+        assert_eq!(visitor.datum_sum, 21);
+    }
+
+    #[test]
+    fn recycling() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.set_recycling::<(&Datum,)>(4);
+
+        for i in 1..=6 {
+            manager.add(GearId::new(i).unwrap(), &Datum { value: i as u32 });
+        }
+
+        assert_eq!(manager.locate(GearId::new(1).unwrap()), None);
+        assert_eq!(manager.locate(GearId::new(2).unwrap()), None);
+
+        let mut values = vec![];
+        manager.iter().run(|(d,): (&Datum,)| values.push(d.value));
+        assert_eq!(values, vec![3, 4, 5, 6]);
+    }
+
+    #[test]
+    fn zero_recycling_capacity() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.set_error_policy(ErrorPolicy::LogAndSkip(|_| {}));
+        manager.set_recycling::<(&Datum,)>(0);
+
+        for i in 1..=3 {
+            manager.add(GearId::new(i).unwrap(), &Datum { value: i as u32 });
+        }
+        let mut count = 0;
+        manager.iter().run(|(_,): (&Datum,)| count += 1);
+        assert_eq!(count, 3);
+    }
+
+    #[test]
+    #[should_panic(expected = "Recycling capacity must be at least 1")]
+    fn zero_recycling_capacity_panics() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.set_recycling::<(&Datum,)>(0);
+    }
+
+    #[test]
+    fn scratch_reuse() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Tag>();
+        for i in 1..=3 {
+            manager.add(GearId::new(i).unwrap(), &Datum { value: i as u32 });
+        }
+
+        let mut scratch = QueryScratch::default();
+        let mut sum = 0;
+        manager.iter_scratch(&mut scratch, |_, (d,): (&Datum,)| sum += d.value);
+        assert_eq!(sum, 6);
+
+        let buffers = (
+            scratch.arg_types.as_ptr(),
+            scratch.type_indices.as_ptr(),
+            scratch.slices.as_ptr(),
+        );
+        for _ in 0..10 {
+            manager.iter_scratch(&mut scratch, |_, (d,): (&mut Datum,)| d.value += 1);
+        }
+        assert_eq!(
+            buffers,
+            (
+                scratch.arg_types.as_ptr(),
+                scratch.type_indices.as_ptr(),
+                scratch.slices.as_ptr()
+            )
+        );
+
+        sum = 0;
+        manager.iter_scratch(&mut scratch, |_, (d,): (&Datum,)| sum += d.value);
+        assert_eq!(sum, 36);
+    }
+
+    #[test]
+    fn prepared_query() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+        manager.add(GearId::new(1).unwrap(), &Datum { value: 1 });
+
+        let mut query = Query::<(&mut Datum,)>::new(&manager);
+        let mut count = 0;
+        query.run(&mut manager, |(d,)| {
+            d.value += 1;
+            count += 1;
+        });
+        assert_eq!(count, 1);
+        assert!(!query.is_stale(&manager));
+
+        manager.add(GearId::new(2).unwrap(), &Datum { value: 10 });
+        manager.add(GearId::new(2).unwrap(), &Position { x: 0, y: 0 });
+        assert!(query.is_stale(&manager));
+
+        let mut values = vec![];
+        query.run(&mut manager, |(d,)| values.push(d.value));
+        values.sort_unstable();
+        assert_eq!(values, vec![2, 10]);
+        assert!(!query.is_stale(&manager));
+
+        manager.register::<Tag>();
+        assert!(query.is_stale(&manager));
+    }
+
+    #[test]
+    fn query_on_another_manager() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+        manager.add(GearId::new(1).unwrap(), &Datum { value: 1 });
+
+        let mut other = GearDataManager::new();
+        other.register::<Position>();
+        other.register::<Datum>();
+        other.add(GearId::new(2).unwrap(), &Datum { value: 2 });
+        assert_eq!(manager.layout_version, other.layout_version);
+
+        let mut query = Query::<(&Datum,)>::new(&manager);
+        assert!(query.is_stale(&other));
+        let mut values = vec![];
+        query.run(&mut other, |(d,)| values.push(d.value));
+        assert_eq!(values, vec![2]);
+    }
+
+    #[test]
+    fn add_to_all() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+        manager.register::<Tag>();
+
+        for i in 1..=10 {
+            let gear_id = GearId::new(i).unwrap();
+            manager.add(gear_id, &Datum { value: i as u32 });
+            if i % 2 == 0 {
+                manager.add(gear_id, &Position { x: i as u32, y: 0 });
+            }
+        }
+        manager.add(GearId::new(11).unwrap(), &Position { x: 11, y: 0 });
+
+        manager.add_to_all::<(&Datum,), _>(&Tag);
+
+        let mut tagged = vec![];
+        manager
+            .iter()
+            .with_tags::<&Tag>()
+            .run_id(|gear_id, (d,): (&Datum,)| {
+                assert_eq!(d.value, gear_id.get() as u32);
+                tagged.push(gear_id.get())
+            });
+        tagged.sort_unstable();
+        assert_eq!(tagged, (1..=10).collect::<Vec<_>>());
+
+        let mut positions = 0;
+        manager
+            .iter()
+            .with_tags::<&Tag>()
+            .run(|(_,): (&Position,)| positions += 1);
+        assert_eq!(positions, 5);
+    }
+
+    #[test]
+    fn remove_from_all() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+        manager.register::<Tag>();
+
+        for i in 1..=6 {
+            let gear_id = GearId::new(i).unwrap();
+            manager.add(gear_id, &Datum { value: i as u32 });
+            if i % 3 == 0 {
+                manager.add(gear_id, &Position { x: i as u32, y: 0 });
+            }
+            manager.add_tag::<Tag>(gear_id);
+        }
+
+        manager.remove_from_all::<Tag>();
+
+        let mut tagged = 0;
+        manager
+            .iter()
+            .with_tags::<&Tag>()
+            .run(|(_,): (&Datum,)| tagged += 1);
+        assert_eq!(tagged, 0);
+
+        let mut sum = 0;
+        manager.iter().run(|(d,): (&Datum,)| sum += d.value);
+        assert_eq!(sum, 21);
+        let mut positions = 0;
+        manager.iter().run(|(p,): (&Position,)| positions += p.x);
+        assert_eq!(positions, 9);
+
+        manager.remove_from_all::<Datum>();
+        assert_eq!(manager.locate(GearId::new(1).unwrap()), None);
+        assert!(manager.locate(GearId::new(3).unwrap()).is_some());
+    }
+
+    #[test]
+    fn query_access() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+
+        let access = manager.query_access::<(&Datum, &mut Position)>().unwrap();
+        assert_eq!(
+            access,
+            QueryAccess {
+                reads: 1,
+                writes: 2
+            }
+        );
+        let other = manager.query_access::<(&Position,)>().unwrap();
+        assert!(queries_conflict(
+            access.writes,
+            access.reads,
+            other.writes,
+            other.reads
+        ));
+        assert_eq!(manager.query_access::<(&Tag,)>(), None);
+    }
+
+    #[test]
+    fn fragmentation() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        assert_eq!(manager.fragmentation_ratio(), 1.0);
+
+        manager.add(GearId::new(1).unwrap(), &Datum { value: 1 });
+        let max_elements = manager.blocks[0].max_elements;
+        for i in 2..=max_elements * 2 {
+            manager.add(GearId::new(i).unwrap(), &Datum { value: i as u32 });
+        }
+        let row_size = (size_of::<Datum>() + size_of::<GearId>()) as f32;
+        let expected = max_elements as f32 * row_size / 32768.0;
+        assert!((manager.fragmentation_ratio() - expected).abs() < 1e-6);
+        assert!(!manager.should_compact());
 
-                for (arg_index, type_index) in type_indices.iter().cloned().enumerate() {
-                    slices[arg_index as usize + 1] = block.component_blocks[type_index as usize]
-                        .unwrap()
-                        .as_ptr()
-                }
+        for i in 11..=max_elements * 2 {
+            manager.remove_all(GearId::new(i).unwrap());
+        }
+        let expected = 10.0 * row_size / (2.0 * 32768.0);
+        assert!((manager.fragmentation_ratio() - expected).abs() < 1e-6);
+        assert!(manager.should_compact());
 
-                unsafe {
-                    T::iter(&slices[..], block.elements_count as usize, |id, x| f(id, x));
-                }
-            }
+        manager.shrink_to_fit();
+        assert_eq!(manager.blocks.len(), 1);
+        assert!((manager.fragmentation_ratio() - expected * 2.0).abs() < 1e-6);
+        assert!(!manager.should_compact());
+    }
+
+    struct CountingAllocator {
+        allocations: Rc<AtomicUsize>,
+        releases: Rc<AtomicUsize>,
+    }
+
+    impl BlockAllocator for CountingAllocator {
+        fn allocate(&mut self, layout: Layout) -> NonNull<u8> {
+            self.allocations.fetch_add(1, Ordering::Relaxed);
+            NonNull::new(unsafe { alloc(layout) }).unwrap()
+        }
+
+        unsafe fn release(&mut self, ptr: NonNull<u8>, layout: Layout) {
+            self.releases.fetch_add(1, Ordering::Relaxed);
+            dealloc(ptr.as_ptr(), layout)
         }
     }
 
-    pub fn iter<T: TypeIter + 'static>(&mut self) -> DataIterator<T> {
-        let mut arg_types = Vec::with_capacity(64);
-        T::get_types(&mut arg_types);
-        let mut type_indices = vec![-1i8; arg_types.len()];
-        let mut selector = 0u64;
+    #[test]
+    fn block_allocator() {
+        let allocations = Rc::new(AtomicUsize::new(0));
+        let releases = Rc::new(AtomicUsize::new(0));
+        let mut manager = GearDataManager::new();
+        manager.set_block_allocator(Box::new(CountingAllocator {
+            allocations: allocations.clone(),
+            releases: releases.clone(),
+        }));
+        manager.register::<Datum>();
+        manager.register::<Position>();
 
-        for (arg_index, type_id) in arg_types.iter().enumerate() {
-            match self.types.iter().position(|t| t == type_id) {
-                Some(i) if selector & (1 << i as u64) != 0 => panic!("Duplicate type"),
-                Some(i) => {
-                    type_indices[arg_index] = i as i8;
-                    selector |= 1 << i as u64;
-                }
-                None => panic!("Unregistered type"),
-            }
+        for i in 1..=4 {
+            manager.add(GearId::new(i).unwrap(), &Datum { value: i as u32 });
         }
-        DataIterator::new(self, selector, type_indices)
+        manager.add(GearId::new(1).unwrap(), &Position { x: 0, y: 0 });
+        assert_eq!(allocations.load(Ordering::Relaxed), 2);
+
+        manager.remove_all(GearId::new(1).unwrap());
+        manager.shrink_to_fit();
+        assert_eq!(releases.load(Ordering::Relaxed), 1);
+
+        let mut sum = 0;
+        manager.iter().run(|(d,): (&Datum,)| sum += d.value);
+        assert_eq!(sum, 9);
+
+        drop(manager);
+        assert_eq!(allocations.load(Ordering::Relaxed), 2);
+        assert_eq!(releases.load(Ordering::Relaxed), 2);
     }
-}
 
-pub struct DataIterator<'a, T> {
-    data: &'a mut GearDataManager,
-    types: u64,
-    type_indices: Vec<i8>,
-    tags: u64,
-    phantom_types: PhantomData<T>,
-}
+    #[test]
+    fn archetype_kernel() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
 
-impl<'a, T: TypeIter + 'static> DataIterator<'a, T> {
-    fn new(
-        data: &'a mut GearDataManager,
-        types: u64,
-        type_indices: Vec<i8>,
-    ) -> DataIterator<'a, T> {
-        Self {
-            data,
-            types,
-            type_indices,
-            tags: 0,
-            phantom_types: PhantomData,
+        for i in 1..=5 {
+            let gear_id = GearId::new(i).unwrap();
+            manager.add(gear_id, &Datum { value: i as u32 });
+            manager.add(gear_id, &Position { x: 0, y: i as u32 });
         }
+
+        let count = manager.with_archetype_mut::<(&mut Datum, &mut Position), _, _>(
+            |ids, (data, positions)| {
+                for ((d, p), id) in data.iter_mut().zip(positions.iter_mut()).zip(ids) {
+                    p.x = d.value + p.y;
+                    d.value = id.get() as u32 * 10;
+                }
+                ids.len()
+            },
+        );
+        assert_eq!(count, Some(5));
+
+        manager.iter().run_id(|id, (d, p): (&Datum, &Position)| {
+            assert_eq!(d.value, id.get() as u32 * 10);
+            assert_eq!(p.x, id.get() as u32 * 2);
+        });
+
+        assert_eq!(
+            manager.with_archetype_mut::<(&mut Datum,), _, _>(|_, _| ()),
+            None
+        );
     }
 
-    pub fn with_tags<U: TypeTuple + 'static>(self) -> Self {
-        let mut tag_types = Vec::with_capacity(64);
-        U::get_types(&mut tag_types);
-        let mut tags = 0;
+    #[test]
+    fn wide_archetype_capacity() {
+        #[derive(Clone)]
+        struct Wide<const N: usize> {
+            _bytes: [u8; 16000],
+        }
 
-        for (i, tag) in self.data.tags.iter().enumerate() {
-            if tag_types.contains(tag) {
-                tags |= 1 << i as u64;
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Wide<0>>();
+        manager.register::<Wide<1>>();
+        manager.register::<Wide<2>>();
+
+        let index = manager.ensure_archetype::<(&Datum,)>().unwrap() as usize;
+        assert_eq!(
+            manager.archetype_capacity::<(&Datum,)>(),
+            Ok(manager.blocks[index].max_elements)
+        );
+        assert_eq!(manager.archetype_capacity::<(&Wide<0>, &Wide<1>)>(), Ok(1));
+        assert_eq!(
+            manager.archetype_capacity::<(&Wide<0>, &Wide<1>, &Wide<2>)>(),
+            Err(ArchetypeTooWide)
+        );
+        let blocks = manager.blocks.len();
+        let result = catch_unwind(AssertUnwindSafe(|| {
+            manager.ensure_archetype::<(&Wide<0>, &Wide<1>, &Wide<2>)>();
+        }));
+        assert!(result.is_err());
+        assert_eq!(manager.blocks.len(), blocks);
+
+        assert_eq!(clamp_block_capacity(10), 10);
+        assert_eq!(clamp_block_capacity(u16::MAX as usize - 1), u16::MAX - 1);
+        assert_eq!(clamp_block_capacity(u16::MAX as usize), u16::MAX - 1);
+        assert_eq!(clamp_block_capacity(usize::MAX), u16::MAX - 1);
+    }
+
+    #[test]
+    fn simd_chunks() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Velocity>();
+        manager.register::<Datum>();
+        for i in 1..=23 {
+            let gear_id = GearId::new(i).unwrap();
+            manager.add(gear_id, &Velocity(i as i32));
+            if i > 20 {
+                manager.add(gear_id, &Datum { value: 0 });
             }
         }
-        Self { tags, ..self }
+
+        let mut scalar_sum = 0;
+        manager.iter().run(|(v,): (&Velocity,)| scalar_sum += v.0);
+
+        let mut lane_sums = [0; 4];
+        let mut remainder_sum = 0;
+        let mut remainders = vec![];
+        manager.for_each_simd::<Velocity, 4, _>(|ids, chunks, remainder| {
+            assert_eq!(ids.len(), chunks.len() * 4 + remainder.len());
+            for chunk in chunks.iter_mut() {
+                for (sum, v) in lane_sums.iter_mut().zip(chunk.iter_mut()) {
+                    *sum += v.0;
+                    v.0 *= 2;
+                }
+            }
+            remainder_sum += remainder.iter().map(|v| v.0).sum::<i32>();
+            remainders.push(remainder.len());
+        });
+        remainders.sort_unstable();
+
+        assert_eq!(remainders, vec![0, 3]);
+        assert_eq!(lane_sums.iter().sum::<i32>() + remainder_sum, scalar_sum);
+        let mut doubled = 0;
+        manager.iter().run(|(v,): (&Velocity,)| doubled += v.0);
+        assert_eq!(doubled, scalar_sum * 2 - remainder_sum);
     }
 
-    #[inline]
-    pub fn run<F: FnMut(T)>(&mut self, mut f: F) {
-        self.run_id(|_, x| f(x))
+    #[test]
+    fn gears_without() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Tag>();
+
+        for i in 1..=10 {
+            let gear_id = GearId::new(i).unwrap();
+            manager.add(gear_id, &Datum { value: i as u32 });
+            if i % 2 == 0 {
+                manager.add_tag::<Tag>(gear_id);
+            }
+        }
+
+        let untagged: Vec<_> = manager
+            .gears_without::<Tag>()
+            .iter()
+            .map(|id| id.get())
+            .collect();
+        assert_eq!(untagged, vec![1, 3, 5, 7, 9]);
+        assert!(manager.gears_without::<Datum>().is_empty());
     }
 
-    #[inline]
-    pub fn run_id<F: FnMut(GearId, T)>(&mut self, f: F) {
-        self.data
-            .run_impl(self.types, self.tags, &self.type_indices, f);
+    #[test]
+    fn single_component_move() {
+        let build = |general: bool| {
+            let mut manager = GearDataManager::new();
+            manager.register::<Datum>();
+            manager.register::<Position>();
+            manager.register::<Velocity>();
+            for i in 1..=5 {
+                let gear_id = GearId::new(i).unwrap();
+                manager.add(gear_id, &Datum { value: i as u32 });
+                if general {
+                    manager.add(gear_id, &Velocity(0));
+                }
+            }
+            for i in [2, 4, 5] {
+                manager.add(GearId::new(i).unwrap(), &Position { x: i as u32, y: 0 });
+            }
+            if general {
+                manager.remove_from_all::<Velocity>();
+            }
+            manager
+        };
+
+        let mut fast = build(false);
+        let general = build(true);
+        let mut positions = vec![];
+        fast.iter().run_id(|gear_id, (d, p): (&Datum, &Position)| {
+            assert_eq!(d.value, p.x);
+            positions.push(gear_id.get());
+        });
+        positions.sort_unstable();
+        assert_eq!(positions, vec![2, 4, 5]);
+        assert_eq!(fast.state_hash(), general.state_hash());
     }
-}
 
-#[cfg(test)]
-mod test {
-    use super::{super::common::GearId, GearDataManager};
+    #[test]
+    fn component_versions() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+        let gear_id = GearId::new(1).unwrap();
+        manager.add(gear_id, &Datum { value: 1 });
 
-    #[derive(Clone)]
-    struct Datum {
-        value: u32,
+        let version = manager.version_of::<Datum>(gear_id).unwrap();
+        assert_eq!(manager.version_of::<Position>(gear_id), None);
+
+        manager.get_mut::<Datum>(gear_id).unwrap().value = 2;
+        let changed = manager.version_of::<Datum>(gear_id).unwrap();
+        assert!(changed > version);
+
+        manager.add(gear_id, &Position { x: 0, y: 0 });
+        assert_eq!(manager.version_of::<Datum>(gear_id), Some(changed));
+
+        let old = manager.replace(gear_id, Datum { value: 3 }).unwrap();
+        assert_eq!(old.value, 2);
+        assert!(manager.version_of::<Datum>(gear_id).unwrap() > changed);
+        assert_eq!(manager.get_mut::<Datum>(gear_id).unwrap().value, 3);
     }
 
-    #[derive(Clone)]
-    struct Tag;
+    #[test]
+    fn id_ranges() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        for i in [7, 3, 1, 2, 8, 5, 9] {
+            manager.add(GearId::new(i).unwrap(), &Datum { value: i as u32 });
+        }
+
+        let mut ranges = vec![];
+        manager.iter_id_ranges(|range, run: &mut [(&mut Datum,)]| {
+            for (d,) in run.iter_mut() {
+                d.value *= 10;
+            }
+            let values: Vec<_> = run.iter().map(|(d,)| d.value / 10).collect();
+            ranges.push((range.start().get(), range.end().get(), values));
+        });
+
+        assert_eq!(
+            ranges,
+            vec![
+                (1, 3, vec![1, 2, 3]),
+                (5, 5, vec![5]),
+                (7, 9, vec![7, 8, 9])
+            ]
+        );
+    }
 
     #[test]
-    fn single_component_iteration() {
+    fn pinned_archetype() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        let max_elements = manager.archetype_capacity::<(&Datum,)>().unwrap();
+
+        for i in 1..=max_elements + 1 {
+            manager.add(GearId::new(i).unwrap(), &Datum { value: i as u32 });
+        }
+        let last = GearId::new(max_elements + 1).unwrap();
+        manager.remove_all(GearId::new(1).unwrap());
+        manager.pin_archetype::<(&Datum,)>();
+
+        let location = manager.locate(last);
+        let ptr = manager.get_mut::<Datum>(last).unwrap() as *mut Datum;
+        manager.shrink_to_fit();
+        assert_eq!(manager.locate(last), location);
+        assert_eq!(manager.get_mut::<Datum>(last).unwrap() as *mut Datum, ptr);
+        assert_eq!(manager.blocks.len(), 2);
+
+        manager.unpin_archetype::<(&Datum,)>();
+        manager.shrink_to_fit();
+        assert_eq!(manager.blocks.len(), 1);
+        assert_eq!(
+            manager.get_mut::<Datum>(last).unwrap().value,
+            last.get() as u32
+        );
+    }
+
+    #[test]
+    fn clear() {
         let mut manager = GearDataManager::new();
         manager.register::<Datum>();
+        manager.register::<Position>();
+        manager.register_boxed::<Large>();
+        let token = Rc::new(());
+        let large = Large {
+            values: [0; 1024],
+            token: token.clone(),
+        };
+
         for i in 1..=5 {
-            manager.add(GearId::new(i as u16).unwrap(), &Datum { value: i });
+            let gear_id = GearId::new(i).unwrap();
+            manager.add(gear_id, &Datum { value: i as u32 });
+            manager.add(gear_id, &Boxed::new(large.clone()));
         }
+        manager.add(GearId::new(6).unwrap(), &Position { x: 0, y: 0 });
+        assert_eq!(manager.reserve_id(GearId::new(10).unwrap()), Ok(()));
+        assert_eq!(Rc::strong_count(&token), 7);
 
-        let mut sum = 0;
-        manager.iter().run(|(d,): (&Datum,)| sum += d.value);
-        assert_eq!(sum, 15);
+        manager.clear();
+        assert_eq!(Rc::strong_count(&token), 2);
+        assert_eq!(manager.reserve_id(GearId::new(10).unwrap()), Err(IdInUse));
+        assert_eq!(manager.reserve_id(GearId::new(6).unwrap()), Ok(()));
+        let blocks = manager.blocks.len();
+        assert!(blocks > 0);
+        assert_eq!(manager.locate(GearId::new(1).unwrap()), None);
+        let mut count = 0;
+        manager.iter().run(|(_,): (&Datum,)| count += 1);
+        assert_eq!(count, 0);
 
-        manager.iter().run(|(d,): (&mut Datum,)| d.value += 1);
-        manager.iter().run(|(d,): (&Datum,)| sum += d.value);
-        assert_eq!(sum, 35);
+        manager.add(GearId::new(1).unwrap(), &Datum { value: 1 });
+        assert_eq!(manager.blocks.len(), blocks);
+
+        manager.clear_and_free();
+        assert_eq!(manager.blocks.len(), 0);
+        assert_eq!(manager.component_id::<Position>(), Some(1));
+        manager.add(GearId::new(2).unwrap(), &Position { x: 1, y: 1 });
+        assert!(manager.locate(GearId::new(2).unwrap()).is_some());
+        assert_eq!(manager.validate(), Ok(()));
     }
 
     #[test]
-    fn tagged_component_iteration() {
+    fn component_ref() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+        for i in 1..=3 {
+            manager.add(GearId::new(i).unwrap(), &Datum { value: i as u32 });
+        }
+
+        let last = GearId::new(3).unwrap();
+        let handle = manager.component_ref::<Datum>(last).unwrap();
+        assert_eq!(handle.get(&manager).unwrap().value, 3);
+
+        manager.remove_all(GearId::new(1).unwrap());
+        assert_eq!(handle.location.get(), (0, 2));
+        assert_eq!(handle.get(&manager).unwrap().value, 3);
+        assert_eq!(handle.location.get(), (0, 0));
+
+        manager.add(last, &Position { x: 0, y: 0 });
+        assert_eq!(handle.get(&manager).unwrap().value, 3);
+        manager.remove::<Datum>(last);
+        assert!(handle.get(&manager).is_none());
+        manager.remove_all(last);
+        assert!(handle.get(&manager).is_none());
+    }
+
+    #[test]
+    fn debug_gear() {
         let mut manager = GearDataManager::new();
         manager.register::<Datum>();
+        manager.register::<Position>();
         manager.register::<Tag>();
-        for i in 1..=10 {
-            let gear_id = GearId::new(i as u16).unwrap();
-            manager.add(gear_id, &Datum { value: i });
+        let gear_id = GearId::new(7).unwrap();
+        manager.add(gear_id, &Datum { value: 0x0102_0304 });
+        manager.add(gear_id, &Position { x: 5, y: 6 });
+        manager.add_tag::<Tag>(gear_id);
+
+        let dump = manager.debug_gear(gear_id);
+        assert!(dump.starts_with("Gear 7 (block 2, index 0)"));
+        assert!(dump.contains("C0 hwphysics::data::test::Datum (4 bytes): [04 03 02 01 ]"));
+        assert!(dump
+            .contains("C1 hwphysics::data::test::Position (8 bytes): [05 00 00 00 06 00 00 00 ]"));
+        assert!(dump.contains("T0"));
+        assert_eq!(
+            manager.debug_gear(GearId::new(1).unwrap()),
+            "Gear 1: no data\n"
+        );
+    }
+
+    #[test]
+    fn block_setup() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+        for i in 1..=6 {
+            let gear_id = GearId::new(i).unwrap();
+            manager.add(gear_id, &Datum { value: i as u32 });
+            if i > 4 {
+                manager.add(gear_id, &Position { x: 0, y: 0 });
+            }
         }
 
-        for i in 1..=10 {
-            let gear_id = GearId::new(i as u16).unwrap();
-            if i & 1 == 0 {
-                manager.add_tag::<Tag>(gear_id);
+        let mut events = vec![];
+        manager.iter_blocks(
+            |types| (types.len(), 0),
+            |(_, sum), _, (d,): (&Datum,)| *sum += d.value,
+            |state| events.push(state),
+        );
+        events.sort_unstable();
+        assert_eq!(events, vec![(1, 10), (2, 11)]);
+    }
+
+    #[test]
+    fn flags() {
+        const BURNING: u8 = 0;
+        const FROZEN: u8 = 5;
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        for i in 1..=4 {
+            manager.add(GearId::new(i).unwrap(), &Datum { value: i as u32 });
+        }
+
+        let gear_id = GearId::new(2).unwrap();
+        manager.set_flag(gear_id, BURNING);
+        manager.set_flag(gear_id, FROZEN);
+        manager.set_flag(GearId::new(3).unwrap(), FROZEN);
+        assert!(manager.has_flag(gear_id, BURNING));
+        assert!(manager.has_flag(gear_id, FROZEN));
+        assert!(!manager.has_flag(GearId::new(1).unwrap(), FROZEN));
+        let archetypes = manager.archetype_count();
+
+        let mut burning = vec![];
+        manager.iter_with_flag(BURNING, |id, (d,): (&Datum,)| {
+            burning.push((id.get(), d.value))
+        });
+        assert_eq!(burning, vec![(2, 2)]);
+
+        manager.clear_flag(gear_id, FROZEN);
+        let mut frozen = vec![];
+        manager.iter_with_flag(FROZEN, |id, (_,): (&Datum,)| frozen.push(id.get()));
+        assert_eq!(frozen, vec![3]);
+        assert_eq!(manager.archetype_count(), archetypes);
+    }
+
+    #[test]
+    fn zero_on_add() {
+        #[derive(Clone)]
+        #[repr(C)]
+        struct Padded {
+            a: u8,
+            b: u32,
+        }
+
+        let mut manager = GearDataManager::new();
+        manager.register::<Padded>();
+        manager.set_zero_on_add(true);
+        let component = manager.component_id::<Padded>().unwrap();
+        let stale = GearId::new(1).unwrap();
+        assert_eq!(manager.add_raw(stale.get(), component, &[0xff; 8]), Ok(()));
+        manager.remove_all(stale);
+
+        let gear_id = GearId::new(2).unwrap();
+        manager.add(gear_id, &Padded { a: 1, b: 2 });
+        assert_eq!(manager.locate(gear_id), Some((0, 0)));
+        let bytes = manager.component_bytes(gear_id, component).unwrap();
+        assert_eq!((bytes[0], &bytes[4..]), (1, &[2, 0, 0, 0][..]));
+        //the padding comes from the new value, not from the removed gear
+        assert_ne!(&bytes[1..4], &[0xff; 3]);
+    }
+
+    #[test]
+    fn removal_journal() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+        for i in 1..=6 {
+            manager.add(GearId::new(i).unwrap(), &Datum { value: i as u32 });
+        }
+        manager.remove_all(GearId::new(1).unwrap());
+        manager.set_journal(true);
+
+        manager.remove_all(GearId::new(4).unwrap());
+        manager.add(GearId::new(5).unwrap(), &Position { x: 0, y: 0 });
+        manager.remove::<Datum>(GearId::new(5).unwrap());
+        manager.remove::<Datum>(GearId::new(2).unwrap());
+        let removed: Vec<_> = manager.drain_removed().iter().map(|id| id.get()).collect();
+        assert_eq!(removed, vec![4, 2]);
+        assert!(manager.drain_removed().is_empty());
+
+        manager.clear();
+        let mut removed: Vec<_> = manager.drain_removed().iter().map(|id| id.get()).collect();
+        removed.sort_unstable();
+        assert_eq!(removed, vec![3, 5, 6]);
+    }
+
+    #[test]
+    fn component_slice() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+        for i in 1..=5 {
+            let gear_id = GearId::new(i).unwrap();
+            manager.add(gear_id, &Datum { value: i as u32 });
+            if i % 2 == 0 {
+                manager.add(gear_id, &Position { x: 0, y: 0 });
             }
         }
 
-        let mut sum = 0;
-        manager.iter().run(|(d,): (&Datum,)| sum += d.value);
-        assert_eq!(sum, 55);
+        let slices = manager.component_slice::<Datum>();
+        assert_eq!(slices.len(), 2);
+        let mut values: Vec<_> = slices.concat().iter().map(|d| d.value).collect();
+        values.sort_unstable();
+        assert_eq!(values, vec![1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn double_buffering() {
+        let mut manager = GearDataManager::new();
+        manager.register_double_buffered::<Velocity>();
+        for i in 1..=3 {
+            manager.add(
+                GearId::new(i).unwrap(),
+                &DoubleBuffered::new(Velocity(i as i32)),
+            );
+        }
 
-        let mut tag_sum = 0;
         manager
             .iter()
-            .with_tags::<&Tag>()
-            .run(|(d,): (&Datum,)| tag_sum += d.value);
-        assert_eq!(tag_sum, 30);
+            .run(|(v,): (&mut DoubleBuffered<Velocity>,)| {
+                let (front, back) = v.split();
+                back.0 = front.0 * 10;
+            });
+
+        let mut fronts = vec![];
+        manager
+            .iter()
+            .run_id(|id, (v,): (&DoubleBuffered<Velocity>,)| {
+                fronts.push((id.get(), v.front().0));
+            });
+        fronts.sort_unstable();
+        assert_eq!(fronts, vec![(1, 1), (2, 2), (3, 3)]);
+
+        manager.swap_buffers::<Velocity>();
+        fronts.clear();
+        manager
+            .iter()
+            .run_id(|id, (v,): (&mut DoubleBuffered<Velocity>,)| {
+                let (front, back) = v.split();
+                assert_eq!(back.0, front.0);
+                fronts.push((id.get(), front.0));
+            });
+        fronts.sort_unstable();
+        assert_eq!(fronts, vec![(1, 10), (2, 20), (3, 30)]);
+    }
+
+    #[test]
+    fn time_sliced_iteration() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+        for i in 1..=9 {
+            let gear_id = GearId::new(i).unwrap();
+            manager.add(gear_id, &Datum { value: 0 });
+            if i % 3 == 0 {
+                manager.add(gear_id, &Position { x: 0, y: 0 });
+            }
+        }
+
+        let mut visited = vec![];
+        manager.iter_id_range(0, 5, |id, (d,): (&mut Datum,)| {
+            d.value += 1;
+            visited.push(id.get());
+        });
+        assert_eq!(visited, vec![1, 2, 3, 4, 5]);
+        manager.iter_id_range(5, 5, |id, (d,): (&mut Datum,)| {
+            d.value += 1;
+            visited.push(id.get());
+        });
+        assert_eq!(visited, (1..=9).collect::<Vec<_>>());
+        manager.iter().run(|(d,): (&Datum,)| assert_eq!(d.value, 1));
+    }
+
+    struct Spawner {
+        next_id: u16,
+    }
+
+    impl System for Spawner {
+        fn run(&mut self, data: &mut GearDataManager) {
+            self.next_id += 1;
+            data.add(GearId::new(self.next_id).unwrap(), &Datum { value: 1 });
+        }
+    }
+
+    #[test]
+    fn schedule() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        let counts = Rc::new(RefCell::new(vec![]));
+        let mut schedule = Schedule::new();
+
+        schedule.add_system(Spawner { next_id: 0 });
+        let recorded = counts.clone();
+        schedule.add_system(move |data: &mut GearDataManager| {
+            let mut count = 0;
+            data.iter().run(|(d,): (&Datum,)| count += d.value);
+            recorded.borrow_mut().push(count);
+        });
+
+        schedule.run(&mut manager);
+        schedule.run(&mut manager);
+        assert_eq!(*counts.borrow(), vec![1, 2]);
+    }
+
+    #[test]
+    fn wide_gear_ids() {
+        use std::num::NonZeroU32;
+
+        let mut manager = GearDataManager::<NonZeroU32>::with_lookup_capacity(16);
+        manager.register::<Datum>();
+        manager.register::<Position>();
+        let gear_id = NonZeroU32::new(70000).unwrap();
+        manager.add(gear_id, &Datum { value: 1 });
+        manager.add(NonZeroU32::new(1).unwrap(), &Datum { value: 2 });
+        assert!(manager.lookup.len() >= 70000);
+
+        manager.add(gear_id, &Position { x: 1, y: 2 });
+        manager.iter().run_id(|id, (d,): (&mut Datum,)| {
+            if id == gear_id {
+                d.value += 10;
+            }
+        });
+        let mut visited = vec![];
+        manager.iter_id_ordered(|id, (d,): (&Datum,)| visited.push((id.get(), d.value)));
+        assert_eq!(visited, [(1, 2), (70000, 11)]);
+
+        manager.remove::<Datum>(gear_id);
+        assert!(!manager.contains::<Datum>(gear_id));
+        assert!(manager.contains::<Position>(gear_id));
+        manager.remove_all(gear_id);
+        assert_eq!(manager.validate(), Ok(()));
     }
 }
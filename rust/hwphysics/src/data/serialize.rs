@@ -0,0 +1,608 @@
+use super::{occupied_slots, BlockMask, GearDataManager, LookupEntry, BLOCK_SIZE};
+use crate::common::{GearId, GearIdType};
+use std::{
+    collections::HashSet,
+    mem::{align_of, size_of},
+    ptr::{copy_nonoverlapping, NonNull},
+    slice,
+};
+
+//larger than any component alignment
+const IMAGE_ALIGNMENT: usize = 128;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum DeserializeError {
+    Truncated,
+    LayoutMismatch,
+    InvalidGearId,
+    NotEmpty,
+}
+
+fn read_bytes<'a>(data: &mut &'a [u8], size: usize) -> Result<&'a [u8], DeserializeError> {
+    if data.len() < size {
+        Err(DeserializeError::Truncated)
+    } else {
+        let (bytes, rest) = data.split_at(size);
+        *data = rest;
+        Ok(bytes)
+    }
+}
+
+fn read_u16(data: &mut &[u8]) -> Result<u16, DeserializeError> {
+    let mut bytes = [0; 2];
+    bytes.copy_from_slice(read_bytes(data, 2)?);
+    Ok(u16::from_le_bytes(bytes))
+}
+
+fn read_gear_id<Id: GearIdType>(bytes: &[u8]) -> Result<Id, DeserializeError> {
+    let mut raw_id = [0; size_of::<usize>()];
+    raw_id[..bytes.len()].copy_from_slice(bytes);
+    Id::from_usize(usize::from_le_bytes(raw_id)).ok_or(DeserializeError::InvalidGearId)
+}
+
+fn read_u32(data: &mut &[u8]) -> Result<u32, DeserializeError> {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(read_bytes(data, 4)?);
+    Ok(u32::from_le_bytes(bytes))
+}
+
+fn read_u64(data: &mut &[u8]) -> Result<u64, DeserializeError> {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(read_bytes(data, 8)?);
+    Ok(u64::from_le_bytes(bytes))
+}
+
+/// An owned copy of the manager's data that can be serialized on another thread.
+pub struct Snapshot<Id = GearId> {
+    element_sizes: Vec<u16>,
+    tags_count: u16,
+    blocks: Vec<(BlockMask, Vec<Id>, Vec<Vec<u8>>)>,
+}
+
+impl<Id: GearIdType> Snapshot<Id> {
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut result = vec![];
+        result.extend_from_slice(&(self.element_sizes.len() as u16).to_le_bytes());
+        for size in &self.element_sizes {
+            result.extend_from_slice(&size.to_le_bytes());
+        }
+        result.extend_from_slice(&self.tags_count.to_le_bytes());
+        result.extend_from_slice(&(self.blocks.len() as u32).to_le_bytes());
+
+        for (mask, gear_ids, components) in &self.blocks {
+            result.extend_from_slice(&mask.type_mask.to_le_bytes());
+            result.extend_from_slice(&mask.tag_mask.to_le_bytes());
+            result.extend_from_slice(&(gear_ids.len() as u16).to_le_bytes());
+
+            for gear_id in gear_ids {
+                result.extend_from_slice(&gear_id.to_usize().to_le_bytes()[..size_of::<Id>()]);
+            }
+            for bytes in components {
+                result.extend_from_slice(bytes);
+            }
+        }
+
+        result
+    }
+}
+
+impl<Id: GearIdType> GearDataManager<Id> {
+    /// The copy is made synchronously, only `Snapshot::serialize` can be deferred.
+    pub fn snapshot(&self) -> Snapshot<Id> {
+        let mut blocks = vec![];
+        for (block, mask) in self.blocks.iter().zip(&self.block_masks) {
+            if block.elements_count == 0 {
+                continue;
+            }
+            let gear_ids = block.gear_ids()[0..block.elements_count as usize].to_vec();
+            let mut components = vec![];
+
+            for (type_index, ptr) in block.component_blocks.iter().enumerate() {
+                if let Some(ptr) = ptr {
+                    let size = self.element_sizes[type_index] as usize;
+                    let bytes = unsafe {
+                        slice::from_raw_parts(ptr.as_ptr(), size * block.elements_count as usize)
+                    };
+                    components.push(bytes.to_vec());
+                }
+            }
+            blocks.push((*mask, gear_ids, components));
+        }
+
+        Snapshot {
+            element_sizes: self.element_sizes[0..self.types.len()].to_vec(),
+            tags_count: self.tags.len() as u16,
+            blocks,
+        }
+    }
+
+    pub fn serialize_compact(&self) -> Vec<u8> {
+        self.snapshot().serialize()
+    }
+
+    fn read_layout(&self, data: &mut &[u8]) -> Result<(), DeserializeError> {
+        if self.blocks.iter().any(|b| b.elements_count > 0) {
+            return Err(DeserializeError::NotEmpty);
+        }
+
+        let types_count = read_u16(data)? as usize;
+        if types_count != self.types.len() || self.drop_fns.iter().any(Option::is_some) {
+            return Err(DeserializeError::LayoutMismatch);
+        }
+        for type_index in 0..types_count {
+            if read_u16(data)? != self.element_sizes[type_index] {
+                return Err(DeserializeError::LayoutMismatch);
+            }
+        }
+        if read_u16(data)? as usize != self.tags.len() {
+            return Err(DeserializeError::LayoutMismatch);
+        }
+        Ok(())
+    }
+
+    fn read_mask(&self, data: &mut &[u8]) -> Result<BlockMask, DeserializeError> {
+        let valid_types = occupied_slots(&self.types);
+        let valid_tags = occupied_slots(&self.tags);
+
+        let mask = BlockMask::new(read_u64(data)?, read_u64(data)?);
+        if mask.type_mask == 0
+            || mask.type_mask & !valid_types != 0
+            || mask.tag_mask & !valid_tags != 0
+        {
+            Err(DeserializeError::LayoutMismatch)
+        } else {
+            Ok(mask)
+        }
+    }
+
+    /// The whole input is validated before the manager is modified, so on error
+    /// the manager is left empty.
+    pub fn deserialize_compact(&mut self, mut data: &[u8]) -> Result<(), DeserializeError> {
+        self.read_layout(&mut data)?;
+
+        let mut blocks = vec![];
+        let mut seen_ids = HashSet::new();
+        for _ in 0..read_u32(&mut data)? {
+            let mask = self.read_mask(&mut data)?;
+            if self.mask_capacity(mask.type_mask) == 0 {
+                return Err(DeserializeError::LayoutMismatch);
+            }
+
+            let count = read_u16(&mut data)? as usize;
+            let mut gear_ids = Vec::with_capacity(count);
+            for bytes in read_bytes(&mut data, count * size_of::<Id>())?.chunks(size_of::<Id>()) {
+                let gear_id = read_gear_id(bytes)?;
+                if !seen_ids.insert(gear_id) {
+                    return Err(DeserializeError::InvalidGearId);
+                }
+                gear_ids.push(gear_id);
+            }
+
+            let mut components = vec![];
+            for type_index in 0..self.types.len() {
+                if mask.type_mask & (1 << type_index as u64) != 0 {
+                    let size = self.element_sizes[type_index] as usize;
+                    components.push((type_index, read_bytes(&mut data, size * count)?));
+                }
+            }
+            blocks.push((mask, gear_ids, components));
+        }
+
+        for (mask, gear_ids, components) in blocks {
+            for (index, gear_id) in gear_ids.into_iter().enumerate() {
+                self.ensure_lookup(gear_id);
+                let block_index = self.ensure_block(mask);
+                let block = &mut self.blocks[block_index as usize];
+                let dest_index = block.elements_count;
+
+                for (type_index, bytes) in &components {
+                    let size = self.element_sizes[*type_index] as usize;
+                    unsafe {
+                        copy_nonoverlapping(
+                            bytes.as_ptr().add(size * index),
+                            block.component_blocks[*type_index]
+                                .unwrap()
+                                .as_ptr()
+                                .add(size * dest_index as usize),
+                            size,
+                        );
+                    }
+                }
+
+                block.gear_ids_mut()[dest_index as usize] = gear_id;
+                block.disabled_components.push(0);
+                block.generations.push(self.generation);
+                block.versions.push(0);
+                block.elements_count += 1;
+                self.lookup[gear_id.to_usize() - 1] = LookupEntry::new(block_index, dest_index);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes a header followed by an image of every non-empty block. Component arrays are
+    /// addressed by offsets relative to the start of their block image.
+    /// The header is padded so that the images start at a multiple of 128 bytes.
+    /// Images hold native-endian data.
+    pub fn serialize_to_mmap_layout(&self) -> Vec<u8> {
+        let mut header = vec![];
+        header.extend_from_slice(&(self.types.len() as u16).to_le_bytes());
+        for size in &self.element_sizes[0..self.types.len()] {
+            header.extend_from_slice(&size.to_le_bytes());
+        }
+        header.extend_from_slice(&(self.tags.len() as u16).to_le_bytes());
+
+        let live_blocks = self.blocks.iter().filter(|b| b.elements_count > 0);
+        header.extend_from_slice(&(live_blocks.count() as u32).to_le_bytes());
+        let mut images = vec![];
+
+        for (block, mask) in self.blocks.iter().zip(&self.block_masks) {
+            if block.elements_count == 0 {
+                continue;
+            }
+            header.extend_from_slice(&mask.type_mask.to_le_bytes());
+            header.extend_from_slice(&mask.tag_mask.to_le_bytes());
+            header.extend_from_slice(&block.elements_count.to_le_bytes());
+
+            let mut image = vec![0; BLOCK_SIZE];
+            for (gear_id, bytes) in block.gear_ids()[0..block.elements_count as usize]
+                .iter()
+                .zip(image.chunks_mut(size_of::<Id>()))
+            {
+                unsafe { (bytes.as_mut_ptr() as *mut Id::Raw).write_unaligned(gear_id.get()) };
+            }
+
+            for (type_index, ptr) in block.component_blocks.iter().enumerate() {
+                if let Some(ptr) = ptr {
+                    let offset = ptr.as_ptr() as usize - block.data.as_ptr() as usize;
+                    let size = self.element_sizes[type_index] as usize;
+                    let length = size * block.elements_count as usize;
+                    header.extend_from_slice(&(offset as u32).to_le_bytes());
+                    unsafe {
+                        copy_nonoverlapping(
+                            ptr.as_ptr(),
+                            image[offset..offset + length].as_mut_ptr(),
+                            length,
+                        );
+                    }
+                }
+            }
+            images.push(image);
+        }
+
+        header.resize(header.len().next_multiple_of(IMAGE_ALIGNMENT), 0);
+        header.extend(images.concat());
+        header
+    }
+
+    /// Attaches the blocks of a `serialize_to_mmap_layout` image in place, without copying.
+    /// The image must be mapped at an address aligned like the one it was written from,
+    /// otherwise the component layout does not match. Attached blocks are never freed.
+    pub fn load_mmap(&mut self, data: &'static mut [u8]) -> Result<(), DeserializeError> {
+        let mut header = &*data;
+        self.read_layout(&mut header)?;
+
+        let mut directory = vec![];
+        for _ in 0..read_u32(&mut header)? {
+            let mask = self.read_mask(&mut header)?;
+            let count = read_u16(&mut header)?;
+            let mut offsets = vec![];
+            for type_index in 0..self.types.len() {
+                if mask.type_mask & (1 << type_index as u64) != 0 {
+                    offsets.push((type_index, read_u32(&mut header)? as usize));
+                }
+            }
+            directory.push((mask, count, offsets));
+        }
+
+        let images_offset = (data.len() - header.len()).next_multiple_of(IMAGE_ALIGNMENT);
+        if data.len() < images_offset + directory.len() * BLOCK_SIZE {
+            return Err(DeserializeError::Truncated);
+        }
+        let images = unsafe { data.as_mut_ptr().add(images_offset) };
+        if images.align_offset(align_of::<Id>()) != 0 {
+            return Err(DeserializeError::LayoutMismatch);
+        }
+
+        let mut blocks = vec![];
+        let mut seen_ids = HashSet::new();
+        for (image_index, (mask, count, offsets)) in directory.into_iter().enumerate() {
+            let image = unsafe { NonNull::new_unchecked(images.add(image_index * BLOCK_SIZE)) };
+            if self.mask_capacity(mask.type_mask) == 0 {
+                return Err(DeserializeError::LayoutMismatch);
+            }
+            let mut block = self.new_block(image, mask);
+            if count > block.max_elements
+                || offsets.iter().any(|(type_index, offset)| {
+                    block.component_blocks[*type_index].map(|ptr| ptr.as_ptr())
+                        != Some(unsafe { image.as_ptr().add(*offset) })
+                })
+            {
+                return Err(DeserializeError::LayoutMismatch);
+            }
+
+            let ids = image.as_ptr() as *const Id::Raw;
+            for index in 0..count as usize {
+                let gear_id =
+                    Id::new(unsafe { *ids.add(index) }).ok_or(DeserializeError::InvalidGearId)?;
+                if !seen_ids.insert(gear_id) {
+                    return Err(DeserializeError::InvalidGearId);
+                }
+            }
+            block.elements_count = count;
+            block.attached = true;
+            blocks.push((block, mask));
+        }
+
+        for (mut block, mask) in blocks {
+            let count = block.elements_count;
+            block.disabled_components.resize(count as usize, 0);
+            block.generations.resize(count as usize, self.generation);
+            block.versions.resize(count as usize, 0);
+            let block_index = self.insert_block(block, mask);
+
+            for index in 0..count {
+                let gear_id = self.blocks[block_index as usize].gear_ids()[index as usize];
+                self.ensure_lookup(gear_id);
+                self.lookup[gear_id.to_usize() - 1] = LookupEntry::new(block_index, index);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{super::GearDataManager, DeserializeError, BLOCK_SIZE, IMAGE_ALIGNMENT};
+    use crate::common::GearId;
+    use std::{
+        alloc::{alloc, Layout},
+        slice,
+    };
+
+    #[derive(Clone)]
+    struct Datum {
+        value: u32,
+    }
+
+    #[derive(Clone)]
+    struct Position {
+        x: u16,
+        y: u16,
+    }
+
+    //attached images must outlive the manager, so they are leaked
+    fn map_image(data: &[u8]) -> &'static mut [u8] {
+        let layout = Layout::from_size_align(data.len(), IMAGE_ALIGNMENT).unwrap();
+        unsafe {
+            let image = slice::from_raw_parts_mut(alloc(layout), data.len());
+            image.copy_from_slice(data);
+            image
+        }
+    }
+
+    fn create_manager() -> GearDataManager {
+        let mut manager = GearDataManager::new();
+        manager.register::<Datum>();
+        manager.register::<Position>();
+        manager
+    }
+
+    #[test]
+    fn size_scales_with_gears() {
+        let mut manager = create_manager();
+        let empty_size = manager.serialize_compact().len();
+
+        let gear_id = GearId::new(1).unwrap();
+        manager.add(gear_id, &Datum { value: 1 });
+        let one_gear_size = manager.serialize_compact().len();
+        assert!(one_gear_size - empty_size < 32);
+
+        manager.add(GearId::new(2).unwrap(), &Datum { value: 2 });
+        let two_gears_size = manager.serialize_compact().len();
+        assert_eq!(two_gears_size - one_gear_size, 2 + 4);
+
+        manager.add(gear_id, &Position { x: 1, y: 1 });
+        manager.remove::<Datum>(gear_id);
+        manager.remove::<Position>(gear_id);
+        assert_eq!(manager.blocks.len(), 3);
+        assert_eq!(manager.serialize_compact().len(), one_gear_size);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut manager = create_manager();
+        for i in 1..=10 {
+            let gear_id = GearId::new(i).unwrap();
+            manager.add(gear_id, &Datum { value: i as u32 });
+            if i % 2 == 0 {
+                manager.add(gear_id, &Position { x: i, y: i * 2 });
+            }
+        }
+        let data = manager.serialize_compact();
+
+        let mut loaded = create_manager();
+        loaded.deserialize_compact(&data).unwrap();
+
+        let mut sum = 0;
+        loaded.iter().run(|(d,): (&Datum,)| sum += d.value);
+        assert_eq!(sum, 55);
+        loaded.iter().run_id(|gear_id, (p,): (&Position,)| {
+            assert_eq!((p.x, p.y), (gear_id.get(), gear_id.get() * 2));
+        });
+
+        assert_eq!(
+            loaded.deserialize_compact(&data),
+            Err(DeserializeError::NotEmpty)
+        );
+        assert_eq!(
+            GearDataManager::new().deserialize_compact(&data),
+            Err(DeserializeError::LayoutMismatch)
+        );
+        assert_eq!(
+            create_manager().deserialize_compact(&data[0..data.len() - 1]),
+            Err(DeserializeError::Truncated)
+        );
+    }
+
+    #[test]
+    fn snapshot_on_thread() {
+        let mut manager = create_manager();
+        for i in 1..=4 {
+            manager.add(GearId::new(i).unwrap(), &Datum { value: i as u32 });
+        }
+        let expected = manager.serialize_compact();
+
+        let snapshot = manager.snapshot();
+        manager.remove_all(GearId::new(1).unwrap());
+        let data = std::thread::spawn(move || snapshot.serialize())
+            .join()
+            .unwrap();
+        assert_eq!(data, expected);
+    }
+
+    #[test]
+    fn mmap_layout_round_trip() {
+        let mut manager = create_manager();
+        for i in 1..=10 {
+            let gear_id = GearId::new(i).unwrap();
+            manager.add(gear_id, &Datum { value: i as u32 });
+            if i % 2 == 0 {
+                manager.add(gear_id, &Position { x: i, y: i * 2 });
+            }
+        }
+        let data = manager.serialize_to_mmap_layout();
+        assert_eq!((data.len() - 2 * BLOCK_SIZE) % IMAGE_ALIGNMENT, 0);
+
+        let image = map_image(&data);
+        let image_range = image.as_ptr_range();
+        let mut loaded = create_manager();
+        loaded.load_mmap(image).unwrap();
+        assert!(loaded
+            .blocks
+            .iter()
+            .all(|block| image_range.contains(&(block.data.as_ptr() as *const u8))));
+
+        let mut sum = 0;
+        loaded.iter().run(|(d,): (&Datum,)| sum += d.value);
+        assert_eq!(sum, 55);
+        loaded.iter().run_id(|gear_id, (p,): (&Position,)| {
+            assert_eq!((p.x, p.y), (gear_id.get(), gear_id.get() * 2));
+        });
+
+        loaded.add(GearId::new(11).unwrap(), &Datum { value: 11 });
+        loaded.remove_all(GearId::new(1).unwrap());
+        sum = 0;
+        loaded.iter().run(|(d,): (&Datum,)| sum += d.value);
+        assert_eq!(sum, 65);
+        assert_eq!(loaded.blocks.len(), 2);
+
+        assert_eq!(
+            loaded.load_mmap(map_image(&data)),
+            Err(DeserializeError::NotEmpty)
+        );
+        assert_eq!(
+            create_manager().load_mmap(map_image(&data[0..data.len() - 1])),
+            Err(DeserializeError::Truncated)
+        );
+    }
+
+    #[test]
+    fn invalid_mmap_image_is_rejected() {
+        let mut manager = create_manager();
+        manager.add(GearId::new(1).unwrap(), &Datum { value: 1 });
+        manager.add(GearId::new(2).unwrap(), &Datum { value: 2 });
+        manager.add(GearId::new(2).unwrap(), &Position { x: 2, y: 2 });
+        let mut data = manager.serialize_to_mmap_layout();
+
+        //gear 2 in the second image now collides with gear 1 in the first one
+        let second_image = data.len() - BLOCK_SIZE;
+        data[second_image..second_image + 2].copy_from_slice(&1u16.to_ne_bytes());
+
+        let mut loaded = create_manager();
+        assert_eq!(
+            loaded.load_mmap(map_image(&data)),
+            Err(DeserializeError::InvalidGearId)
+        );
+        assert!(loaded.blocks.is_empty());
+
+        let mut wide_manager = GearDataManager::new();
+        wide_manager.register::<Large>();
+        wide_manager.register::<Huge>();
+        let mut data = wide_manager.serialize_to_mmap_layout();
+        data.truncate(12);
+        data[8..12].copy_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&0b11u64.to_le_bytes());
+        data.extend_from_slice(&0u64.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.resize(IMAGE_ALIGNMENT + BLOCK_SIZE, 0);
+
+        assert_eq!(
+            wide_manager.load_mmap(map_image(&data)),
+            Err(DeserializeError::LayoutMismatch)
+        );
+        assert!(wide_manager.blocks.is_empty());
+    }
+
+    #[derive(Clone)]
+    struct Large {
+        _bytes: [u8; 20000],
+    }
+
+    #[derive(Clone)]
+    struct Huge {
+        _bytes: [u8; 20000],
+    }
+
+    #[test]
+    fn failed_deserialize_keeps_manager_empty() {
+        let mut manager = create_manager();
+        for i in 1..=3 {
+            manager.add(GearId::new(i).unwrap(), &Datum { value: i as u32 });
+        }
+        manager.add(GearId::new(1).unwrap(), &Position { x: 1, y: 1 });
+        let data = manager.serialize_compact();
+
+        //the first id of the Datum block now collides with gear 1 in the second block
+        let mut corrupted = data.clone();
+        corrupted[30..32].copy_from_slice(&1u16.to_le_bytes());
+
+        let mut loaded = create_manager();
+        assert_eq!(
+            loaded.deserialize_compact(&corrupted),
+            Err(DeserializeError::InvalidGearId)
+        );
+        let mut count = 0;
+        loaded.iter().run(|(_,): (&Datum,)| count += 1);
+        assert_eq!(count, 0);
+
+        loaded.deserialize_compact(&data).unwrap();
+        loaded.iter().run(|(_,): (&Datum,)| count += 1);
+        assert_eq!(count, 3);
+    }
+
+    #[test]
+    fn too_wide_archetype_is_rejected() {
+        let mut manager = GearDataManager::new();
+        manager.register::<Large>();
+        manager.register::<Huge>();
+
+        let mut data = manager.serialize_compact();
+        data[8..12].copy_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&0b11u64.to_le_bytes());
+        data.extend_from_slice(&0u64.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend_from_slice(&1u16.to_le_bytes());
+        data.extend(vec![0; 40000]);
+
+        assert_eq!(
+            manager.deserialize_compact(&data),
+            Err(DeserializeError::LayoutMismatch)
+        );
+        assert!(manager.blocks.is_empty());
+    }
+}